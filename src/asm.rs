@@ -0,0 +1,907 @@
+// Assembler: turns Meri assembly source into the program bytes the CPU executes.
+use std::collections::HashMap;
+use std::num::ParseIntError;
+
+use crate::run::{Instructions, MachineConfig, OpcodeMap, OperandFormat, OperandType, DEST_INDIRECT, INSTRUCTION_SIZE, LOOP_COUNTER, SRC_INDIRECT};
+
+// Parses an immediate value, which may also be negative (-128 to -1) to give a signed byte its
+// two's complement encoding, e.g. `-1` is 255. Non-negative values are parsed like `parse_number`.
+fn parse_immediate(value_str: &str) -> Result<u8, ParseIntError> {
+    if value_str.starts_with('-') {
+        value_str.parse::<i8>().map(|value| value as u8)
+    } else {
+        parse_number(value_str)
+    }
+}
+
+// Alternative names for registers, accepted wherever a register operand is expected.
+// Every alias must name a register that exists on the smallest machine (R0).
+const REGISTER_ALIASES: [(&str, u8); 1] = [
+    ("ACC", 0), // Accumulator
+];
+
+// Parses a numeric literal that fits in a byte: decimal (`255`), hexadecimal (`0xFF`) or
+// binary (`0b11111111`).
+pub fn parse_number(number_str: &str) -> Result<u8, ParseIntError> {
+    if let Some(hex) = number_str.strip_prefix("0x").or_else(|| number_str.strip_prefix("0X")) {
+        u8::from_str_radix(hex, 16)
+    } else if let Some(binary) = number_str.strip_prefix("0b").or_else(|| number_str.strip_prefix("0B")) {
+        u8::from_str_radix(binary, 2)
+    } else {
+        number_str.parse::<u8>()
+    }
+}
+
+// Helper function for the lexer to parse register (R#), memory (M#) or register-indirect ([R#])
+// operands. Register aliases such as `ACC` are accepted as well, also inside brackets.
+// It returns the numerical value (index or address) and its corresponding `OperandType`.
+// Bounds are checked against the machine the program is assembled for.
+fn parse_reg_mem_operand(operand_str: &str, machine: &MachineConfig) -> Result<(u8, OperandType), String> {
+    if let Some(&(_, reg_idx)) = REGISTER_ALIASES.iter().find(|&&(alias, _)| alias == operand_str) {
+        return Ok((reg_idx, OperandType::Register));
+    }
+    if let Some(inner) = operand_str.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        // Indirect operand: the register holds the RAM address to use.
+        return match parse_reg_mem_operand(inner, machine)? {
+            (reg_idx, OperandType::Register) => Ok((reg_idx, OperandType::Indirect)),
+            _ => Err(format!("Expected a register inside brackets, e.g. [R0], found '{}'.", operand_str)),
+        };
+    }
+    if let Some(index_str) = operand_str.strip_prefix('R') {
+        // Parse register index
+        let reg_idx = index_str.parse::<u8>()
+            .map_err(|e| format!("Invalid register index '{}': {}", operand_str, e))?;
+        // Validate register index bounds
+        if reg_idx as usize >= machine.register_count {
+            return Err(format!("Register index {} out of bounds (max {}).", reg_idx, machine.register_count - 1));
+        }
+        Ok((reg_idx, OperandType::Register))
+    } else if let Some(addr_str) = operand_str.strip_prefix('M') {
+        // Parse memory address
+        let mem_addr = parse_number(addr_str)
+            .map_err(|e| format!("Invalid memory address '{}': {}", operand_str, e))?;
+        // Validate memory address bounds
+        if mem_addr as usize >= machine.memory_size {
+            return Err(format!("Memory address {} out of bounds (max {}).", mem_addr, machine.memory_size - 1));
+        }
+        Ok((mem_addr, OperandType::Memory))
+    } else {
+        // If neither R# nor M# format is found, it's an error for this type of operand.
+        Err(format!("Expected register (R#), memory (M#) or indirect ([R#]) operand, found '{}'.", operand_str))
+    }
+}
+
+// Whether a token can be used as a label name: a letter or underscore followed by letters,
+// digits or underscores, e.g. `loop` or `end_2`.
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// An assembly error and the source line it was found on. It displays as `Line 3: <message>`,
+// and converts into that string for callers that only report errors as text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeriError {
+    pub line: usize,     // 1-based line the failing statement starts on.
+    pub message: String, // What is wrong with it, without the line number.
+}
+
+impl std::fmt::Display for MeriError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for MeriError {}
+
+impl From<MeriError> for String {
+    fn from(error: MeriError) -> String {
+        error.to_string()
+    }
+}
+
+// Stateful assembler that converts human-readable assembly source into a byte vector
+// that the Meri CPU emulator can execute.
+// Source is fed one line at a time with `add_line`, and the final program is obtained
+// with `finish`. A line ending in a backslash `\` is continued on the next line. A line that
+// fails to assemble leaves the program unchanged (discarding any lines it continued), so
+// callers can report the error and keep feeding further lines.
+// Labels (`loop:`) may be used as jump addresses before they are defined: such operands are
+// assembled as 0 and patched in `finish`, once every label's address is known.
+// Constants (`.equ MAX 10`), on the other hand, must be defined before they are used.
+// `.input 3` declares how many `In` values the program expects, which `input_count` reports.
+// With `set_base`, the program is assembled to be loaded at a nonzero address instead of 0.
+pub struct Assembler {
+    program: Vec<u8>,       // Bytes assembled so far.
+    labels: HashMap<String, (usize, usize)>, // Byte offset and defining line of each label.
+    label_refs: Vec<(usize, String, usize)>, // Byte index, label name and line of each label operand to patch.
+    constants: HashMap<String, (u8, usize)>, // Value and defining line of each `.equ` constant.
+    lines_read: usize,      // Number of source lines fed so far.
+    line_num: usize,        // Line the statement being assembled starts on, used for error messages.
+    continued: Option<(usize, String)>, // Start line and text of a statement continued with `\`.
+    input_count: Option<(usize, usize)>, // Input value count and line of the `.input` directive, if any.
+    base: usize,            // Address the program will be loaded at, added to every label address.
+    opcode_map: OpcodeMap,  // Opcode numbering to encode instructions with.
+    machine: MachineConfig, // Register count and memory size that operands are checked against.
+}
+
+// The error for source that ends in the middle of a statement continued from `start_line`.
+fn unfinished_continuation(start_line: usize) -> MeriError {
+    MeriError { line: start_line, message: String::from("Instruction continued with '\\' at the end of the source.") }
+}
+
+// The part of a line before its "//" or "#" comment, whichever comes first.
+pub fn strip_comment(line: &str) -> &str {
+    let comment_start = [line.find("//"), line.find('#')].into_iter().flatten().min().unwrap_or(line.len());
+    &line[..comment_start]
+}
+
+impl Assembler {
+    // Creates an empty assembler that encodes opcodes with the given map for the given machine.
+    pub fn new(opcode_map: OpcodeMap, machine: MachineConfig) -> Self {
+        Assembler {
+            program: Vec::new(),
+            labels: HashMap::new(),
+            label_refs: Vec::new(),
+            constants: HashMap::new(),
+            lines_read: 0,
+            line_num: 0,
+            continued: None,
+            input_count: None,
+            base: 0,
+            opcode_map,
+            machine,
+        }
+    }
+
+    // Relocates the program to be loaded at `base`: every label stands for its offset plus `base`,
+    // and the program must fit in the memory above it. Numeric jump addresses are left as written.
+    pub fn set_base(&mut self, base: u8) {
+        self.base = base as usize;
+    }
+
+    // Assembles a single source line and appends its instructions to the program.
+    // The line may contain several instructions separated by semicolons and a trailing `//` or `#` comment.
+    // `;` only ever separates instructions; it does not start a comment.
+    pub fn add_line(&mut self, line: &str) -> Result<(), MeriError> {
+        self.assemble_line(line).map_err(|message| MeriError { line: self.line_num, message })
+    }
+
+    // Does the work of `add_line`, returning errors without their line number.
+    fn assemble_line(&mut self, line: &str) -> Result<(), String> {
+        self.lines_read += 1;
+
+        let line_part = strip_comment(line).trim();
+
+        // A trailing backslash continues the statement on the next line: collect the text for now.
+        if let Some(head) = line_part.strip_suffix('\\') {
+            let start_line = self.lines_read;
+            let (_, text) = self.continued.get_or_insert_with(|| (start_line, String::new()));
+            text.push_str(head);
+            text.push(' ');
+            return Ok(());
+        }
+
+        // Join any continued lines with this one; errors point at the line the statement starts on.
+        let (start_line, joined) = match self.continued.take() {
+            Some((start_line, text)) => (start_line, text + line_part),
+            None => (self.lines_read, line_part.to_string()),
+        };
+        self.line_num = start_line;
+        let instruction_part = joined.trim();
+
+        // Skip empty lines or lines that were entirely comments
+        if instruction_part.is_empty() {
+            return Ok(());
+        }
+
+        // A constant definition takes up the whole line.
+        if let Some(definition) = instruction_part.strip_prefix(".equ") {
+            return self.define_constant(definition);
+        }
+        if let Some(declaration) = instruction_part.strip_prefix(".input") {
+            return self.declare_input_count(declaration);
+        }
+
+        // Assemble into local buffers first so that a failing line leaves the assembler untouched.
+        let mut line_bytes = Vec::new();
+        let mut line_labels: Vec<(String, usize)> = Vec::new();
+        let mut line_label_refs = Vec::new();
+
+        // Split the instruction line by semicolon to handle multiple instructions on one line
+        // (though current examples usually have one per line)
+        for part in instruction_part.split(';') {
+            let mut trimmed_part = part.trim(); // Remove leading/trailing whitespace
+
+            // Leading `name:` tokens define labels at the address of the instruction that follows.
+            while let Some((label, rest)) = trimmed_part.split_once(':') {
+                let label = label.trim();
+                if label.contains(char::is_whitespace) {
+                    break; // The colon belongs to something else, not a leading label.
+                }
+                if !is_label_name(label) {
+                    return Err(format!("Invalid label name '{}'.", label));
+                }
+                if let Some((_, line)) = self.labels.get(label) {
+                    return Err(format!("Duplicate label '{}' (first defined on line {}).", label, line));
+                }
+                if let Some((_, line)) = self.constants.get(label) {
+                    return Err(format!("Label '{}' has the name of the constant defined on line {}.", label, line));
+                }
+                if line_labels.iter().any(|(other, _)| other == label) {
+                    return Err(format!("Duplicate label '{}'.", label));
+                }
+                line_labels.push((label.to_string(), self.program.len() + line_bytes.len()));
+                trimmed_part = rest.trim();
+            }
+
+            if trimmed_part.is_empty() {
+                continue;
+            }
+            if let Some(values) = trimmed_part.strip_prefix(".byte") {
+                line_bytes.extend(self.assemble_byte_directive(values)?);
+                continue;
+            }
+            let (bytes, label_ref) = self.assemble_instruction(trimmed_part)?;
+            if let Some(label) = label_ref {
+                // The address operand is byte 2 of the instruction.
+                line_label_refs.push((self.program.len() + line_bytes.len() + 2, label, self.line_num));
+            }
+            line_bytes.extend_from_slice(&bytes);
+        }
+
+        // Everything has to fit in program memory, where the program is loaded.
+        if self.base + self.program.len() + line_bytes.len() > self.machine.memory_size {
+            let load_address = if self.base > 0 { format!(", loaded at address {}", self.base) } else { String::new() };
+            return Err(format!(
+                "Program exceeds the {}-byte program memory ({} bytes with this line{}).",
+                self.machine.memory_size, self.program.len() + line_bytes.len(), load_address
+            ));
+        }
+
+        self.program.extend_from_slice(&line_bytes);
+        for (label, offset) in line_labels {
+            self.labels.insert(label, (offset, self.line_num));
+        }
+        self.label_refs.extend(line_label_refs);
+        Ok(())
+    }
+
+    // Consumes the assembler and returns the assembled program bytes.
+    pub fn finish(self) -> Result<Vec<u8>, MeriError> {
+        if let Some((start_line, _)) = self.continued {
+            return Err(unfinished_continuation(start_line));
+        }
+
+        // Now that every label is known, fill in the addresses of label operands.
+        let mut program = self.program;
+        for (index, label, line) in &self.label_refs {
+            let error = |message| MeriError { line: *line, message };
+            let (offset, _) = self.labels.get(label).ok_or_else(|| error(format!("Undefined label '{}'.", label)))?;
+            // A label after `.byte` data that isn't padded to a multiple of 4 is not at an instruction.
+            if !offset.is_multiple_of(INSTRUCTION_SIZE as usize) {
+                return Err(error(format!("Label '{}' is at offset {}, which is not a multiple of the {}-byte instruction size.", label, offset, INSTRUCTION_SIZE)));
+            }
+            program[*index] = u8::try_from(self.base + offset)
+                .map_err(|_| error(format!("Label '{}' is at address {}, beyond the addressable range.", label, self.base + offset)))?;
+        }
+        Ok(program)
+    }
+
+    // Records the constant of an `.equ <NAME> <VALUE>` directive (e.g. `.equ MAX 10`), given the text
+    // after `.equ`. Names share the namespace of labels, and a name can't be defined twice.
+    fn define_constant(&mut self, definition: &str) -> Result<(), String> {
+        if !definition.is_empty() && !definition.starts_with(char::is_whitespace) {
+            return Err(format!("Unknown directive '.equ{}'.", definition.split_whitespace().next().unwrap_or("")));
+        }
+        let definition = definition.trim().trim_end_matches(';');
+        let mut tokens = definition.split_whitespace();
+        let (Some(name), Some(value_str), None) = (tokens.next(), tokens.next(), tokens.next()) else {
+            return Err(String::from("Expected format: .equ <NAME> <VALUE>"));
+        };
+        if !is_label_name(name) {
+            return Err(format!("Invalid constant name '{}'.", name));
+        }
+        if let Some((_, line)) = self.constants.get(name) {
+            return Err(format!("Duplicate constant '{}' (first defined on line {}).", name, line));
+        }
+        if let Some((_, line)) = self.labels.get(name) {
+            return Err(format!("Constant '{}' has the name of the label defined on line {}.", name, line));
+        }
+        // A jump to a name that wasn't defined yet was taken to be a label.
+        if let Some((_, _, line)) = self.label_refs.iter().find(|(_, label, _)| label == name) {
+            return Err(format!("Constant '{}' is defined after its use on line {}.", name, line));
+        }
+        let value = parse_immediate(value_str)
+            .map_err(|e| format!("Invalid constant value '{}' (expected -128 to 255): {}", value_str, e))?;
+        self.constants.insert(name.to_string(), (value, self.line_num));
+        Ok(())
+    }
+
+    // Records the count of an `.input <N>` directive (e.g. `.input 3`), given the text after `.input`.
+    fn declare_input_count(&mut self, declaration: &str) -> Result<(), String> {
+        if !declaration.is_empty() && !declaration.starts_with(char::is_whitespace) {
+            return Err(format!("Unknown directive '.input{}'.", declaration.split_whitespace().next().unwrap_or("")));
+        }
+        if let Some((_, line)) = self.input_count {
+            return Err(format!("Duplicate .input directive (first declared on line {}).", line));
+        }
+        let count_str = declaration.trim().trim_end_matches(';').trim();
+        let count = count_str
+            .parse::<usize>()
+            .map_err(|e| format!("Invalid input count '{}'. Expected format: .input <N>: {}", count_str, e))?;
+        self.input_count = Some((count, self.line_num));
+        Ok(())
+    }
+
+    // The labels defined so far with the addresses they stand for (including the base), in address
+    // order and, at the same address, in the order they were defined. A label after the last byte of
+    // a program that fills the address space has no address and is left out.
+    pub fn labels(&self) -> Vec<(String, u8)> {
+        let mut labels: Vec<(usize, usize, &String)> = self.labels.iter().map(|(name, &(offset, line))| (self.base + offset, line, name)).collect();
+        labels.sort();
+        labels.into_iter().filter_map(|(address, _, name)| u8::try_from(address).ok().map(|address| (name.clone(), address))).collect()
+    }
+
+    // The number of input values declared with `.input`, if the source declared one.
+    pub fn input_count(&self) -> Option<usize> {
+        self.input_count.map(|(count, _)| count)
+    }
+
+    // Looks up the value of a constant defined earlier with `.equ`.
+    fn constant(&self, name: &str) -> Result<u8, String> {
+        self.constants.get(name).map(|&(value, _)| value).ok_or_else(|| {
+            format!("Undefined constant '{}' (constants must be defined with .equ before they are used).", name)
+        })
+    }
+
+    // Parses the comma-separated values of a `.byte` directive (e.g. `.byte 1, 0x20, 0b11`)
+    // into the raw bytes it places in the program.
+    fn assemble_byte_directive(&self, values: &str) -> Result<Vec<u8>, String> {
+        if !values.is_empty() && !values.starts_with(char::is_whitespace) {
+            return Err(format!("Unknown directive '.byte{}'.", values.split_whitespace().next().unwrap_or("")));
+        }
+        if values.trim().is_empty() {
+            return Err(String::from("Missing values for directive '.byte'. Expected format: .byte <VALUE>, <VALUE>, ..."));
+        }
+        values
+            .split(',')
+            .map(|value| {
+                let value = value.trim();
+                match parse_immediate(value) {
+                    Ok(byte) => Ok(byte),
+                    Err(_) if is_label_name(value) => self.constant(value),
+                    Err(e) => Err(format!("Invalid byte value '{}' (expected -128 to 255): {}", value, e)),
+                }
+            })
+            .collect()
+    }
+
+    // Encodes a single instruction (without separators or comments) into its 4-byte form.
+    // It handles the generalized instruction syntax and encodes addressing modes.
+    // If the address operand is a label, its name is returned too, and the operand is left as 0 to patch later.
+    fn assemble_instruction(&self, trimmed_part: &str) -> Result<([u8; 4], Option<String>), String> {
+        // Split the instruction line into tokens (opcode and operands).
+        let mut tokens = trimmed_part.split_whitespace().peekable();
+        // The first token is expected to be the opcode string.
+        let opcode_str = tokens.next().ok_or_else(|| String::from("Empty instruction part after semicolon."))?;
+
+        // Look up the instruction by mnemonic; its operand format decides how the rest of the line is parsed.
+        let instruction = Instructions::from_mnemonic(opcode_str)
+            .ok_or_else(|| format!("Unknown opcode: {}", opcode_str))?; // Error for unrecognized instruction.
+        let opcode_val = self.opcode_map.encode(instruction);
+        // `Loop` counts down a fixed register, which has to exist on this machine.
+        if instruction == Instructions::Loop && self.machine.register_count <= LOOP_COUNTER as usize {
+            return Err(format!("Loop needs register R{} as its counter, but the machine only has {} registers.", LOOP_COUNTER, self.machine.register_count));
+        }
+
+        // Label used as the address operand, if any.
+        let mut label_ref = None;
+
+        // Variables to hold the components of the 4-byte instruction.
+        let instruction_bytes: [u8; 4] = match instruction.operand_format() {
+            OperandFormat::DestSrc => {
+                // These instructions expect two operands (destination and source).
+                let dest_str = tokens.next().ok_or_else(|| format!("Missing destination operand for instruction '{}'. Expected format: {} <DEST> <SOURCE>", opcode_str, opcode_str))?;
+                let src_str = tokens.next().ok_or_else(|| format!("Missing source operand for instruction '{}'. Expected format: {} <DEST> <SOURCE>", opcode_str, opcode_str))?;
+
+                // Parse destination and source operands using the helper function.
+                let (dest_val, dest_type) = parse_reg_mem_operand(dest_str, &self.machine)?;
+                let (src_val, src_type) = parse_reg_mem_operand(src_str, &self.machine)?;
+
+                let mut mode_byte = 0; // Initialize mode byte to 0
+
+                // Encode addressing modes into the `mode_byte`:
+                // Bit 0 (0b01) for destination type: 1 if Memory, 0 if Register.
+                // Bit 1 (0b10) for source type: 1 if Memory, 0 if Register.
+                // `DEST_INDIRECT` and `SRC_INDIRECT` instead mark a register-indirect `[R#]` operand.
+                mode_byte |= dest_type.mode_bits(0b01, DEST_INDIRECT);
+                mode_byte |= src_type.mode_bits(0b10, SRC_INDIRECT);
+                [opcode_val, mode_byte, dest_val, src_val]
+            },
+            OperandFormat::DestImm => {
+                // These instructions (MovImm, AddImm, ...) expect a destination (R#/M#) and an immediate value.
+                let dest_str = tokens.next().ok_or_else(|| format!("Missing destination operand for instruction '{}'. Expected format: {} <DEST> <VALUE>", opcode_str, opcode_str))?;
+                let value_str = tokens.next().ok_or_else(|| format!("Missing immediate value for instruction '{}'. Expected format: {} <DEST> <VALUE>", opcode_str, opcode_str))?;
+
+                let (dest_val, dest_type) = parse_reg_mem_operand(dest_str, &self.machine)?;
+                
+                let immediate_value = match parse_immediate(value_str) {
+                    Ok(value) => value,
+                    Err(_) if is_label_name(value_str) => self.constant(value_str)?,
+                    Err(e) => return Err(format!("Invalid immediate value '{}' (expected -128 to 255): {}", value_str, e)),
+                };
+
+                let mut mode_byte = 0;
+                // Encode destination type into mode_byte. There is no source operand to encode.
+                mode_byte |= dest_type.mode_bits(0b01, DEST_INDIRECT);
+                [opcode_val, mode_byte, dest_val, immediate_value]
+            },
+            OperandFormat::Single => {
+                // These instructions expect one operand.
+                let op_str = tokens.next().ok_or_else(|| format!("Missing operand for instruction '{}'. Expected format: {} <OPERAND>", opcode_str, opcode_str))?;
+                let (op_val, op_type) = parse_reg_mem_operand(op_str, &self.machine)?;
+
+                let mut mode_byte = 0;
+                // Encode addressing mode for the single operand into the `mode_byte`.
+                mode_byte |= op_type.mode_bits(0b01, DEST_INDIRECT); // Only the destination bits, as it's the only operand.
+                [opcode_val, mode_byte, op_val, 0] // operand2_val is 0 for single-operand instructions
+            },
+            OperandFormat::Address => {
+                // These instructions expect one address operand: a number, a constant or a label.
+                let addr_str = tokens.next().ok_or_else(|| format!("Missing address for instruction '{}'. Expected format: {} <ADDRESS>", opcode_str, opcode_str))?;
+                let address_val = match parse_number(addr_str) {
+                    Ok(address) => address,
+                    Err(_) if self.constants.contains_key(addr_str) => self.constant(addr_str)?,
+                    Err(_) if is_label_name(addr_str) => {
+                        label_ref = Some(addr_str.to_string());
+                        0 // Patched in `finish`.
+                    }
+                    Err(e) => return Err(format!("Invalid jump address '{}': {}", addr_str, e)),
+                };
+                // Instructions start at multiples of 4, so any other address would be jumping into the middle of one.
+                if !address_val.is_multiple_of(INSTRUCTION_SIZE) {
+                    return Err(format!("Jump address {} is not a multiple of the {}-byte instruction size.", address_val, INSTRUCTION_SIZE));
+                }
+
+                // mode_byte and operand2_val remain 0 as they are not applicable for jumps.
+                [opcode_val, 0, address_val, 0]
+            },
+            OperandFormat::Offset => {
+                // JmpRel expects a signed offset in bytes from its own address, e.g. -8 to go back two instructions.
+                let offset_str = tokens.next().ok_or_else(|| format!("Missing offset for instruction '{}'. Expected format: {} <OFFSET>", opcode_str, opcode_str))?;
+                let offset_val = offset_str.parse::<i8>()
+                    .map_err(|e| format!("Invalid jump offset '{}' (expected -128 to 127): {}", offset_str, e))?;
+                if offset_val % INSTRUCTION_SIZE as i8 != 0 {
+                    return Err(format!("Jump offset {} is not a multiple of the {}-byte instruction size.", offset_val, INSTRUCTION_SIZE));
+                }
+                [opcode_val, 0, offset_val as u8, 0]
+            },
+            OperandFormat::CompareSwap => {
+                // Cas expects a memory address (M#), an expected-value register and a new-value register.
+                let addr_str = tokens.next().ok_or_else(|| format!("Missing address operand for instruction '{}'. Expected format: {} <ADDR> <EXPECTED_REG> <NEW_REG>", opcode_str, opcode_str))?;
+                let expected_str = tokens.next().ok_or_else(|| format!("Missing expected-value register for instruction '{}'. Expected format: {} <ADDR> <EXPECTED_REG> <NEW_REG>", opcode_str, opcode_str))?;
+                let new_str = tokens.next().ok_or_else(|| format!("Missing new-value register for instruction '{}'. Expected format: {} <ADDR> <EXPECTED_REG> <NEW_REG>", opcode_str, opcode_str))?;
+
+                let (addr_val, addr_type) = parse_reg_mem_operand(addr_str, &self.machine)?;
+                let (expected_val, expected_type) = parse_reg_mem_operand(expected_str, &self.machine)?;
+                let (new_val, new_type) = parse_reg_mem_operand(new_str, &self.machine)?;
+
+                // Operand types are fixed for Cas, so the mode byte is free to hold the address.
+                if addr_type != OperandType::Memory {
+                    return Err(format!("Expected memory (M#) operand for '{}' address, found '{}'.", opcode_str, addr_str));
+                }
+                if expected_type != OperandType::Register || new_type != OperandType::Register {
+                    return Err(format!("Expected register (R#) operands for '{}' expected and new values.", opcode_str));
+                }
+                [opcode_val, addr_val, expected_val, new_val]
+            },
+            OperandFormat::DestPort => {
+                // InP expects a destination (R#/M#) and a port number.
+                let dest_str = tokens.next().ok_or_else(|| format!("Missing destination operand for instruction '{}'. Expected format: {} <DEST> <PORT>", opcode_str, opcode_str))?;
+                let port_str = tokens.next().ok_or_else(|| format!("Missing port number for instruction '{}'. Expected format: {} <DEST> <PORT>", opcode_str, opcode_str))?;
+
+                let (dest_val, dest_type) = parse_reg_mem_operand(dest_str, &self.machine)?;
+                let port_val = parse_number(port_str)
+                    .map_err(|e| format!("Invalid port number '{}': {}", port_str, e))?;
+
+                let mut mode_byte = 0;
+                mode_byte |= dest_type.mode_bits(0b01, DEST_INDIRECT);
+                [opcode_val, mode_byte, dest_val, port_val]
+            },
+            OperandFormat::PortSrc => {
+                // OutP expects a port number and a source (R#/M#).
+                let port_str = tokens.next().ok_or_else(|| format!("Missing port number for instruction '{}'. Expected format: {} <PORT> <SRC>", opcode_str, opcode_str))?;
+                let src_str = tokens.next().ok_or_else(|| format!("Missing source operand for instruction '{}'. Expected format: {} <PORT> <SRC>", opcode_str, opcode_str))?;
+
+                let port_val = parse_number(port_str)
+                    .map_err(|e| format!("Invalid port number '{}': {}", port_str, e))?;
+                let (src_val, src_type) = parse_reg_mem_operand(src_str, &self.machine)?;
+
+                // The source keeps its usual mode bits (bit 1), so the port sits in the destination slot.
+                let mut mode_byte = 0;
+                mode_byte |= src_type.mode_bits(0b10, SRC_INDIRECT);
+                [opcode_val, mode_byte, port_val, src_val]
+            },
+            OperandFormat::RegisterPair => {
+                // Rol16/Ror16 expect the even register naming a pair: R0 (R0:R1) or R2 (R2:R3).
+                let pair_str = tokens.next().ok_or_else(|| format!("Missing register pair for instruction '{}'. Expected format: {} <PAIR>", opcode_str, opcode_str))?;
+                let (pair_val, pair_type) = parse_reg_mem_operand(pair_str, &self.machine)?;
+                if pair_type != OperandType::Register || pair_val % 2 != 0 || pair_val as usize + 1 >= self.machine.register_count {
+                    return Err(format!("Expected register pair R0 (R0:R1) or R2 (R2:R3) for '{}', found '{}'.", opcode_str, pair_str));
+                }
+                [opcode_val, 0, pair_val, 0]
+            },
+            OperandFormat::Output => {
+                // Out expects a source (R#/M#), optionally followed by `char` to print it as a character.
+                let src_str = tokens.next().ok_or_else(|| format!("Missing source operand for instruction '{}'. Expected format: {} <SRC> [char]", opcode_str, opcode_str))?;
+                let (src_val, src_type) = parse_reg_mem_operand(src_str, &self.machine)?;
+
+                // Like single-operand instructions, the operand's type goes in the destination bits.
+                let mut mode_byte = 0;
+                mode_byte |= src_type.mode_bits(0b01, DEST_INDIRECT);
+                if tokens.next_if_eq(&"char").is_some() {
+                    mode_byte |= 0b100;
+                }
+                [opcode_val, mode_byte, src_val, 0]
+            },
+            OperandFormat::RawBytes => {
+                // Ext expects three byte values, passed to the host's extension handler as they are.
+                let mut bytes = [opcode_val, 0, 0, 0];
+                for byte in &mut bytes[1..] {
+                    let value_str = tokens.next().ok_or_else(|| format!("Missing byte operand for instruction '{}'. Expected format: {} <BYTE> <BYTE> <BYTE>", opcode_str, opcode_str))?;
+                    *byte = match parse_immediate(value_str) {
+                        Ok(value) => value,
+                        Err(_) if is_label_name(value_str) => self.constant(value_str)?,
+                        Err(e) => return Err(format!("Invalid byte value '{}' (expected -128 to 255): {}", value_str, e)),
+                    };
+                }
+                bytes
+            },
+            OperandFormat::NoOperands => {
+                // HLT takes no operands. All operand values and mode_byte remain 0.
+                [opcode_val, 0, 0, 0]
+            },
+        };
+        
+        // After parsing, check if there are any unexpected extra tokens on the line.
+        if tokens.next().is_some() {
+            return Err(format!("Too many operands or unexpected tokens for instruction '{}' on line: '{}'.", opcode_str, trimmed_part));
+        }
+
+        Ok((instruction_bytes, label_ref))
+    }
+}
+
+// Extracts the assembly from ```meri fenced blocks in a mixed text file, for literate-style lessons.
+// Every line outside a fenced block (including the fence markers) is replaced with an empty line,
+// so line numbers in lexer errors still point at the original file.
+pub fn extract_fenced(source: &str) -> Result<String, String> {
+    let mut extracted = String::new();
+    let mut fence_start: Option<usize> = None; // Line number of the currently open fence, if any.
+
+    for (line_num, line) in source.lines().enumerate() {
+        let trimmed = line.trim();
+        match fence_start {
+            None if trimmed == "```meri" => fence_start = Some(line_num + 1),
+            Some(_) if trimmed == "```" => fence_start = None,
+            Some(_) => extracted.push_str(line),
+            None => {}
+        }
+        extracted.push('\n');
+    }
+
+    if let Some(start) = fence_start {
+        return Err(format!("Line {}: Unterminated ```meri block.", start));
+    }
+    Ok(extracted)
+}
+
+// The lexer function converts a whole source file into a program byte vector.
+// It is a one-shot wrapper around `Assembler`. Given a `skipped` list, lines that fail to assemble
+// are skipped instead, with their errors collected there, and emit no bytes, so later instructions move up.
+pub fn lexer(source: &str, opcode_map: &OpcodeMap, machine: &MachineConfig, skipped: Option<&mut Vec<String>>) -> Result<Vec<u8>, String> {
+    assemble_program(source, opcode_map, machine, 0, skipped).map(|program| program.bytes)
+}
+
+// A whole assembled source file: the program bytes and what the source said about them.
+#[derive(Debug, Clone)]
+pub struct AssembledProgram {
+    pub bytes: Vec<u8>,               // The program, to be loaded at the base it was assembled for.
+    pub input_count: Option<usize>,   // Input value count declared with `.input`, if any.
+    pub labels: Vec<(String, u8)>,    // Every label with the address it stands for, see `Assembler::labels`.
+}
+
+// Like `lexer`, but assembles the program to be loaded at `base` (see `Assembler::set_base`) and
+// also returns the `.input` count and labels of the source.
+pub fn assemble_program(source: &str, opcode_map: &OpcodeMap, machine: &MachineConfig, base: u8, mut skipped: Option<&mut Vec<String>>) -> Result<AssembledProgram, String> {
+    let mut assembler = Assembler::new(opcode_map.clone(), *machine);
+    assembler.set_base(base);
+    for line in source.lines() {
+        match (assembler.add_line(line), skipped.as_deref_mut()) {
+            (Err(e), Some(skipped)) => skipped.push(e.to_string()),
+            (result, _) => result?,
+        }
+    }
+    if let Some(skipped) = skipped {
+        if let Some((start_line, _)) = assembler.continued.take() {
+            skipped.push(unfinished_continuation(start_line).to_string());
+        }
+    }
+    let input_count = assembler.input_count();
+    let labels = assembler.labels();
+    Ok(AssembledProgram { bytes: assembler.finish()?, input_count, labels })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Assembles source for the default machine.
+    fn assemble(source: &str) -> Result<Vec<u8>, String> {
+        lexer(source, &OpcodeMap::default(), &MachineConfig::default(), None)
+    }
+
+    #[test]
+    fn labels_resolve_forward_and_backward() {
+        let program = assemble("start: Inc R0\nJmpAddr end\nJmpAddr start\nend: HLT\n").unwrap();
+        let jmp = Instructions::JmpAddr.opcode();
+        assert_eq!(&program[4..12], &[jmp, 0, 12, 0, jmp, 0, 0, 0]);
+    }
+
+    #[test]
+    fn duplicate_label_is_rejected() {
+        let error = assemble("here: Inc R0\nhere: HLT\n").unwrap_err();
+        assert_eq!(error, "Line 2: Duplicate label 'here' (first defined on line 1).");
+    }
+
+    #[test]
+    fn undefined_label_is_rejected() {
+        let error = assemble("Inc R0\nJmpAddr nowhere\n").unwrap_err();
+        assert_eq!(error, "Line 2: Undefined label 'nowhere'.");
+    }
+
+    #[test]
+    fn indirect_operands_set_their_mode_bits() {
+        let program = assemble("Mov R0 [R1]\nMov [R2] M3\n").unwrap();
+        assert_eq!(&program[0..4], &[Instructions::Mov.opcode(), SRC_INDIRECT, 0, 1]);
+        assert_eq!(&program[4..8], &[Instructions::Mov.opcode(), DEST_INDIRECT | 0b10, 2, 3]);
+    }
+
+    #[test]
+    fn indirect_operand_needs_a_register() {
+        assert!(assemble("Mov R0 [M1]\n").unwrap_err().contains("Expected a register inside brackets"));
+    }
+
+    #[test]
+    fn negative_immediates_use_twos_complement() {
+        let program = assemble("MovImm R0 -1\nMovImm R1 -128\nMovImm R2 255\n").unwrap();
+        assert_eq!((program[3], program[7], program[11]), (0xFF, 0x80, 0xFF));
+        assert!(assemble("MovImm R0 -129\n").unwrap_err().contains("Invalid immediate value '-129'"));
+    }
+
+    #[test]
+    fn constant_defined_after_its_use_is_rejected() {
+        let error = assemble("JmpAddr LIMIT\n.equ LIMIT 8\n").unwrap_err();
+        assert_eq!(error, "Line 2: Constant 'LIMIT' is defined after its use on line 1.");
+    }
+
+    #[test]
+    fn constant_defined_before_its_use_is_substituted() {
+        let program = assemble(".equ LIMIT 8\nMovImm R0 LIMIT\n").unwrap();
+        assert_eq!(program, vec![Instructions::MovImm.opcode(), 0, 0, 8]);
+    }
+
+    #[test]
+    fn constant_can_be_a_jump_address() {
+        let program = assemble(".equ END 8\nJmpAddr END\nInc R0\nHLT\n").unwrap();
+        assert_eq!(&program[0..4], &[Instructions::JmpAddr.opcode(), 0, 8, 0]);
+    }
+
+    #[test]
+    fn constant_can_not_be_redefined() {
+        let error = assemble(".equ MAX 10\nInc R0\n.equ MAX 11\n").unwrap_err();
+        assert_eq!(error, "Line 3: Duplicate constant 'MAX' (first defined on line 1).");
+    }
+
+    #[test]
+    fn constant_used_before_its_definition_is_rejected() {
+        let error = assemble("MovImm R0 MAX\n.equ MAX 10\n").unwrap_err();
+        assert_eq!(error, "Line 1: Undefined constant 'MAX' (constants must be defined with .equ before they are used).");
+    }
+
+    #[test]
+    fn misaligned_jump_targets_are_rejected() {
+        assert!(assemble(".equ MAX 7\nJmpAddr MAX\n").unwrap_err().contains("Jump address 7 is not a multiple"));
+        assert!(assemble("JmpRel 3\n").unwrap_err().contains("Jump offset 3 is not a multiple"));
+        assert!(assemble("JmpAddr data\n.byte 1\ndata: HLT\n").unwrap_err().contains("Label 'data' is at offset 5"));
+    }
+
+    #[test]
+    fn loop_needs_its_counter_register() {
+        let machine = MachineConfig::new(256, 3).unwrap();
+        let error = lexer("Loop 0\n", &OpcodeMap::default(), &machine, None).unwrap_err();
+        assert_eq!(error, "Line 1: Loop needs register R3 as its counter, but the machine only has 3 registers.");
+    }
+
+    #[test]
+    fn input_directive_declares_the_input_count() {
+        let program = assemble_program(".input 2\nIn R0\nIn R1\n", &OpcodeMap::default(), &MachineConfig::default(), 0, None).unwrap();
+        assert_eq!(program.bytes.len(), 8);
+        assert_eq!(program.input_count, Some(2));
+        assert_eq!(assemble(".input 1\n.input 2\n").unwrap_err(), "Line 2: Duplicate .input directive (first declared on line 1).");
+    }
+
+    #[test]
+    fn memory_operands_are_checked_against_the_configured_memory_size() {
+        let small = MachineConfig::new(128, 4).unwrap();
+        let large = MachineConfig::new(256, 4).unwrap();
+        assert!(lexer("Mov R0 M200\n", &OpcodeMap::default(), &large, None).is_ok());
+        let error = lexer("Mov R0 M200\n", &OpcodeMap::default(), &small, None).unwrap_err();
+        assert_eq!(error, "Line 1: Memory address 200 out of bounds (max 127).");
+        assert!(lexer("Mov R0 M127\n", &OpcodeMap::default(), &small, None).is_ok());
+    }
+
+    #[test]
+    fn base_address_relocates_labels() {
+        let mut assembler = Assembler::new(OpcodeMap::default(), MachineConfig::default());
+        assembler.set_base(16);
+        assembler.add_line("JmpAddr end").unwrap();
+        assembler.add_line("end: HLT").unwrap();
+        assert_eq!(&assembler.finish().unwrap()[0..4], &[Instructions::JmpAddr.opcode(), 0, 20, 0]);
+    }
+
+    #[test]
+    fn labels_are_listed_by_address() {
+        let program = assemble_program("main: Call helper\nHLT\nhelper: inner: Ret\n", &OpcodeMap::default(), &MachineConfig::default(), 4, None).unwrap();
+        let labels = [("main", 4), ("helper", 12), ("inner", 12)].map(|(name, address)| (name.to_string(), address));
+        assert_eq!(program.labels, labels);
+    }
+
+    #[test]
+    fn base_address_leaves_less_room_for_the_program() {
+        let machine = MachineConfig::new(16, 4).unwrap();
+        let error = assemble_program("Inc R0\nInc R0\n", &OpcodeMap::default(), &machine, 12, None).unwrap_err();
+        assert_eq!(error, "Line 2: Program exceeds the 16-byte program memory (8 bytes with this line, loaded at address 12).");
+    }
+
+    #[test]
+    fn fenced_blocks_keep_their_line_numbers() {
+        let source = "# Lesson\nSome prose.\n```meri\nInc R0\nBogus R1\n```\nMore prose.\n";
+        let extracted = extract_fenced(source).unwrap();
+        assert_eq!(extracted, "\n\n\nInc R0\nBogus R1\n\n\n");
+        assert_eq!(assemble(&extracted).unwrap_err(), "Line 5: Unknown opcode: Bogus");
+    }
+
+    #[test]
+    fn unterminated_fence_is_rejected() {
+        assert_eq!(extract_fenced("text\n```meri\nInc R0\n").unwrap_err(), "Line 2: Unterminated ```meri block.");
+    }
+
+    #[test]
+    fn assembler_accepts_source_line_by_line() {
+        let mut assembler = Assembler::new(OpcodeMap::default(), MachineConfig::default());
+        assembler.add_line("JmpAddr done // forward reference").unwrap();
+        assembler.add_line("Inc R0; Inc R1").unwrap();
+        assembler.add_line("done: HLT").unwrap();
+        let program = assembler.finish().unwrap();
+        assert_eq!(program.len(), 16);
+        assert_eq!(&program[0..4], &[Instructions::JmpAddr.opcode(), 0, 12, 0]);
+    }
+
+    #[test]
+    fn assembler_reports_errors_with_their_line() {
+        let mut assembler = Assembler::new(OpcodeMap::default(), MachineConfig::default());
+        assembler.add_line("Inc R0").unwrap();
+        let error = assembler.add_line("Inc R9").unwrap_err();
+        assert_eq!(error, MeriError { line: 2, message: String::from("Register index 9 out of bounds (max 3).") });
+        assert_eq!(error.to_string(), "Line 2: Register index 9 out of bounds (max 3).");
+    }
+
+    #[test]
+    fn assembler_rejects_unfinished_continuation() {
+        let mut assembler = Assembler::new(OpcodeMap::default(), MachineConfig::default());
+        assembler.add_line("Mov R0 \\").unwrap();
+        assert_eq!(assembler.finish().unwrap_err().to_string(), "Line 1: Instruction continued with '\\' at the end of the source.");
+    }
+
+    #[test]
+    fn undefined_label_error_points_at_its_use() {
+        let mut assembler = Assembler::new(OpcodeMap::default(), MachineConfig::default());
+        assembler.add_line("Inc R0").unwrap();
+        assembler.add_line("JmpAddr nowhere").unwrap();
+        assembler.add_line("HLT").unwrap();
+        assert_eq!(assembler.finish().unwrap_err(), MeriError { line: 2, message: String::from("Undefined label 'nowhere'.") });
+    }
+
+    #[test]
+    fn continued_instruction_assembles_like_a_single_line() {
+        let continued = assemble("Cas M10 \\\n    R0 R1\nHLT\n").unwrap();
+        assert_eq!(continued, assemble("Cas M10 R0 R1\nHLT\n").unwrap());
+    }
+
+    #[test]
+    fn continued_instruction_errors_point_at_its_first_line() {
+        let error = assemble("Inc R0\nCas M10 \\\n    R0 R9\n").unwrap_err();
+        assert_eq!(error, "Line 2: Register index 9 out of bounds (max 3).");
+        assert_eq!(assemble("Inc R0\nCas M10 \\\n    R0 R1\nBogus\n").unwrap_err(), "Line 4: Unknown opcode: Bogus");
+    }
+
+    #[test]
+    fn best_effort_skips_malformed_lines_with_a_warning() {
+        let mut skipped = Vec::new();
+        let program = lexer("Inc R0\nBogus R1\nInc R0\nHLT\n", &OpcodeMap::default(), &MachineConfig::default(), Some(&mut skipped)).unwrap();
+        assert_eq!(program, assemble("Inc R0\nInc R0\nHLT\n").unwrap()); // The bad line emits nothing.
+        assert_eq!(skipped, ["Line 2: Unknown opcode: Bogus"]);
+    }
+
+    #[test]
+    fn hex_and_binary_literals_match_decimal() {
+        let decimal = assemble("MovImm R0 255\nAddImm R1 1\nMov R0 M10\nJmpAddr 16\n").unwrap();
+        assert_eq!(assemble("MovImm R0 0xFF\nAddImm R1 0b0001\nMov R0 M0xA\nJmpAddr 0x10\n").unwrap(), decimal);
+        assert_eq!(assemble("MovImm R0 0b11111111\nAddImm R1 0x1\nMov R0 M0b1010\nJmpAddr 0b10000\n").unwrap(), decimal);
+    }
+
+    #[test]
+    fn out_of_range_hex_literal_is_rejected() {
+        assert!(assemble("Inc R0\nMovImm R0 0x100\n").unwrap_err().starts_with("Line 2: Invalid immediate value '0x100'"));
+    }
+
+    #[test]
+    fn byte_directive_emits_its_values() {
+        let program = assemble("HLT\ntable: .byte 1, 0x20, 0b11, -1\n").unwrap();
+        assert_eq!(&program[4..], &[1, 0x20, 0b11, 0xFF]);
+        assert!(assemble("Inc R0\n.byte 1, 256\n").unwrap_err().starts_with("Line 2: Invalid byte value '256'"));
+        assert_eq!(assemble(".byte\n").unwrap_err(), "Line 1: Missing values for directive '.byte'. Expected format: .byte <VALUE>, <VALUE>, ...");
+    }
+
+    #[test]
+    fn acc_assembles_like_r0() {
+        assert_eq!(assemble("Mov ACC R1\n").unwrap(), assemble("Mov R0 R1\n").unwrap());
+        assert_eq!(assemble("Add R1 [ACC]\n").unwrap(), assemble("Add R1 [R0]\n").unwrap());
+        let error = assemble("Mov AX R1\n").unwrap_err();
+        assert!(error.contains("Expected register (R#), memory (M#) or indirect ([R#]) operand, found 'AX'."), "{}", error);
+    }
+
+    #[test]
+    fn hash_and_slash_comments_are_stripped() {
+        let plain = assemble("Inc R0\nHLT\n").unwrap();
+        assert_eq!(assemble("Inc R0 // bump\nHLT\n").unwrap(), plain);
+        assert_eq!(assemble("Inc R0 # bump\n# whole line\nHLT\n").unwrap(), plain);
+        assert_eq!(assemble("Inc R0 # first // second\nHLT // done # really\n").unwrap(), plain);
+        assert_eq!(strip_comment("Inc R0 # a // b"), "Inc R0 ");
+        assert_eq!(strip_comment("Inc R0 // a # b"), "Inc R0 ");
+    }
+
+    #[test]
+    fn semicolons_separate_instructions_rather_than_start_comments() {
+        assert_eq!(assemble("Inc R0; HLT\n").unwrap(), assemble("Inc R0\nHLT\n").unwrap());
+        assert_eq!(assemble("Inc R0; # Inc R1\nHLT\n").unwrap(), assemble("Inc R0\nHLT\n").unwrap());
+        assert!(assemble("Inc R0; bump\nHLT\n").is_err());
+    }
+
+    #[test]
+    fn mnemonics_are_case_insensitive() {
+        let mixed = assemble("MovImm R0 2\nloop: Dec R0\nJmpNe loop\nHLT\n").unwrap();
+        assert_eq!(assemble("MOVIMM R0 2\nloop: DEC R0\nJMPNE loop\nHLT\n").unwrap(), mixed);
+        assert_eq!(assemble("movimm R0 2\nloop: dec R0\njmpne loop\nhlt\n").unwrap(), mixed);
+        assert_eq!(assemble("mOvImM R0 2\nloop: dEc R0\njMpNe loop\nHlT\n").unwrap(), mixed);
+    }
+
+    #[test]
+    fn operands_stay_case_sensitive() {
+        assert!(assemble("Mov r0 R1\n").is_err());
+        assert!(assemble("Mov R0 m1\n").is_err());
+        assert!(assemble("loop: JmpAddr LOOP\n").is_err());
+    }
+
+    #[test]
+    fn negative_movimm_and_jmprel_operands_encode_as_signed_bytes() {
+        let program = assemble("MovImm R0 -1\nMovImm R1 -128\nJmpRel -4\nJmpRel -128\n").unwrap();
+        assert_eq!((program[3], program[7], program[10], program[14]), (255, 128, 0xFC, 0x80));
+        assert_eq!(assemble("HLT\nMovImm R0 256\n").unwrap_err(), "Line 2: Invalid immediate value '256' (expected -128 to 255): number too large to fit in target type");
+        assert!(assemble("HLT\nJmpRel -129\n").unwrap_err().starts_with("Line 2: Invalid jump offset '-129' (expected -128 to 127)"));
+        assert!(assemble("JmpRel 128\n").unwrap_err().starts_with("Line 1: Invalid jump offset '128' (expected -128 to 127)"));
+    }
+}
@@ -0,0 +1,81 @@
+use crate::reduce::error_signature;
+use meri::run::{self, RunOptions, RunOutcome};
+
+// Instructions each program may execute before the run is stopped, so that a
+// non-terminating submission still gets compared (it will end with a step-limit error).
+const EQUIV_STEP_LIMIT: u64 = 100_000;
+
+// Describes how a run ended, for the outcome line of a difference report.
+fn describe_ending(outcome: &RunOutcome) -> String {
+    match &outcome.error {
+        Some(error) => format!("error ({})", error),
+        None => String::from("completed"),
+    }
+}
+
+// Runs two programs under identical options (and therefore identical RAM preloads and port
+// inputs) and lists every observable difference between them: how the run ended, final
+// register and RAM values, `Out` output and port output. An empty list means the programs are equivalent.
+// Errors are compared without the PC they occurred at, since equivalent programs can differ in layout.
+pub fn compare_programs(program: &[u8], other: &[u8], options: &RunOptions) -> Result<Vec<String>, String> {
+    let first = run::run_silently(program, options, EQUIV_STEP_LIMIT)?;
+    let second = run::run_silently(other, options, EQUIV_STEP_LIMIT)?;
+
+    let mut differences = Vec::new();
+    if first.error.as_deref().map(error_signature) != second.error.as_deref().map(error_signature) {
+        differences.push(format!("Outcome: {} vs {}", describe_ending(&first), describe_ending(&second)));
+    }
+    for (index, (a, b)) in first.registers.iter().zip(&second.registers).enumerate() {
+        if a != b {
+            differences.push(format!("R{}: {} vs {}", index, a, b));
+        }
+    }
+    for (address, (a, b)) in first.ram.iter().zip(&second.ram).enumerate() {
+        if a != b {
+            differences.push(format!("M{}: {} vs {}", address, a, b));
+        }
+    }
+    if first.output != second.output {
+        differences.push(format!(
+            "Output: {:?} vs {:?}",
+            String::from_utf8_lossy(&first.output), String::from_utf8_lossy(&second.output)
+        ));
+    }
+    for (port, (a, b)) in first.port_outputs.iter().zip(&second.port_outputs).enumerate() {
+        if a != b {
+            differences.push(format!("Port {} output: {:?} vs {:?}", port, a, b));
+        }
+    }
+    Ok(differences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use meri::asm::lexer;
+    use meri::run::{MachineConfig, OpcodeMap};
+
+    // Compares two sources assembled for the default machine, with no preloads.
+    fn compare(source: &str, other: &str) -> Vec<String> {
+        let assemble = |source: &str| lexer(source, &OpcodeMap::default(), &MachineConfig::default(), None).unwrap();
+        compare_programs(&assemble(source), &assemble(other), &RunOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn differently_written_programs_with_the_same_behavior_are_equivalent() {
+        let differences = compare("MovImm R0 2\nAdd R0 R0\nOut R0\nHLT\n", "MovImm R0 4\nOut R0\nHLT\n");
+        assert_eq!(differences, Vec::<String>::new());
+    }
+
+    #[test]
+    fn different_output_is_reported() {
+        let differences = compare("MovImm R0 5\nOut R0\nMovImm R0 0\nHLT\n", "MovImm R0 7\nOut R0\nMovImm R0 0\nHLT\n");
+        assert_eq!(differences, vec![String::from("Output: \"5\\n\" vs \"7\\n\"")]);
+    }
+
+    #[test]
+    fn subtly_different_state_is_reported() {
+        let differences = compare("MovImm M3 1\nHLT\n", "MovImm M4 1\nHLT\n");
+        assert_eq!(differences, vec![String::from("M3: 1 vs 0"), String::from("M4: 0 vs 1")]);
+    }
+}
@@ -0,0 +1,152 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use meri::run::{Instructions, MachineConfig, OperandFormat, INSTRUCTION_SIZE, LOOP_COUNTER};
+
+// Small xorshift64* pseudo-random number generator, so generation needs no external crates.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The xorshift state must never be zero.
+        Rng { state: seed.max(1) }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // Returns a value in `0..bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+// Derives a seed from the current time, so every run produces a different program.
+pub fn seed_from_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(1)
+}
+
+// Generates a random register (R#) or memory (M#) operand within the machine's bounds. Memory
+// addresses are taken from `data_start` up, and only registers are used if that leaves none.
+fn reg_mem_operand(rng: &mut Rng, machine: &MachineConfig, data_start: usize) -> String {
+    if rng.below(2) == 0 || data_start >= machine.memory_size {
+        format!("R{}", rng.below(machine.register_count))
+    } else {
+        format!("M{}", memory_address(rng, machine, data_start))
+    }
+}
+
+// Generates a random memory address from `data_start` to the end of memory, which must not be empty.
+fn memory_address(rng: &mut Rng, machine: &MachineConfig, data_start: usize) -> usize {
+    data_start + rng.below(machine.memory_size - data_start)
+}
+
+// Generates the assembly source of a random valid program with `count` instructions, for fuzzing
+// the emulator. The last instruction is always HLT, and jumps only ever target a later instruction,
+// so every generated program assembles, decodes and runs to completion.
+// IDiv, Div, Mod, InP, In, Pop and Ret are never generated since they can fault on a zero divisor,
+// missing input or an empty stack, Ext since no extension handler is registered, and Dbg and Out
+// are skipped to keep output quiet.
+// Operands stay within `machine`, and the program must fit in its program memory.
+// For `--von-neumann`, where memory operands address the program itself, they only address the
+// memory past its end, and Push and Call, which write the stack at the top of memory, are not
+// generated either, so the program never overwrites its own instructions.
+pub fn random_program(count: usize, seed: u64, machine: &MachineConfig, von_neumann: bool) -> Result<String, String> {
+    let max_instructions = machine.memory_size / INSTRUCTION_SIZE as usize;
+    if count == 0 || count > max_instructions {
+        return Err(format!("Random program size must be between 1 and {} instructions.", max_instructions));
+    }
+    let data_start = if von_neumann { count * INSTRUCTION_SIZE as usize } else { 0 };
+
+    let candidates: Vec<Instructions> = Instructions::ALL
+        .iter()
+        .copied()
+        .filter(|instruction| !matches!(instruction, Instructions::HLT | Instructions::IDiv | Instructions::Div | Instructions::Mod | Instructions::Dbg | Instructions::Out | Instructions::InP | Instructions::In | Instructions::Pop | Instructions::Ret | Instructions::Ext))
+        // Register pair instructions need at least the pair R0:R1.
+        .filter(|instruction| instruction.operand_format() != OperandFormat::RegisterPair || machine.register_count >= 2)
+        // Loop counts down R3, which smaller machines don't have.
+        .filter(|instruction| *instruction != Instructions::Loop || machine.register_count > LOOP_COUNTER as usize)
+        // Cas needs a memory address, and the stack would overlap the program in von Neumann mode.
+        .filter(|instruction| *instruction != Instructions::Cas || data_start < machine.memory_size)
+        .filter(|instruction| !von_neumann || !matches!(instruction, Instructions::Push | Instructions::Call))
+        .collect();
+
+    let mut rng = Rng::new(seed);
+    let mut source = format!("// Randomly generated program (seed {})\n", seed);
+    for index in 0..count - 1 {
+        let instruction = candidates[rng.below(candidates.len())];
+        let operands = match instruction.operand_format() {
+            OperandFormat::DestSrc => format!(" {} {}", reg_mem_operand(&mut rng, machine, data_start), reg_mem_operand(&mut rng, machine, data_start)),
+            OperandFormat::DestImm => format!(" {} {}", reg_mem_operand(&mut rng, machine, data_start), rng.below(256)),
+            OperandFormat::Single => format!(" {}", reg_mem_operand(&mut rng, machine, data_start)),
+            OperandFormat::Address => {
+                // Pick any later instruction (up to and including the final HLT) as the target.
+                let target = index + 1 + rng.below(count - index - 1);
+                format!(" {}", target * INSTRUCTION_SIZE as usize)
+            }
+            OperandFormat::Offset => {
+                // Jump forward to a later instruction within reach of a signed byte (at most 31 instructions).
+                let distance = 1 + rng.below((count - index - 1).min(31));
+                format!(" {}", distance * INSTRUCTION_SIZE as usize)
+            }
+            OperandFormat::CompareSwap => format!(
+                " M{} R{} R{}",
+                memory_address(&mut rng, machine, data_start),
+                rng.below(machine.register_count),
+                rng.below(machine.register_count)
+            ),
+            OperandFormat::DestPort => format!(" {} {}", reg_mem_operand(&mut rng, machine, data_start), rng.below(256)),
+            OperandFormat::PortSrc => format!(" {} {}", rng.below(256), reg_mem_operand(&mut rng, machine, data_start)),
+            OperandFormat::RegisterPair => format!(" R{}", rng.below(machine.register_count / 2) * 2),
+            OperandFormat::Output => format!(" {}", reg_mem_operand(&mut rng, machine, data_start)),
+            OperandFormat::RawBytes => format!(" {} {} {}", rng.below(256), rng.below(256), rng.below(256)),
+            OperandFormat::NoOperands => String::new(),
+        };
+        source.push_str(&format!("{}{};\n", instruction.mnemonic(), operands));
+    }
+    source.push_str("HLT;\n");
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use meri::asm::lexer;
+    use meri::run::{run_silently, OpcodeMap, RunOptions};
+
+    // Generates, assembles and runs programs for a range of seeds, failing on any runtime error.
+    fn run_generated(count: usize, von_neumann: bool) {
+        let options = RunOptions { von_neumann, ..RunOptions::default() };
+        for seed in 0..50 {
+            let source = random_program(count, seed, &options.machine, von_neumann).unwrap();
+            let program = lexer(&source, &OpcodeMap::default(), &options.machine, None).unwrap();
+            let outcome = run_silently(&program, &options, 1000).unwrap();
+            assert_eq!(outcome.error, None, "seed {}:\n{}", seed, source);
+        }
+    }
+
+    #[test]
+    fn generated_program_assembles_and_runs_to_completion() {
+        run_generated(20, false);
+    }
+
+    #[test]
+    fn generated_program_does_not_overwrite_itself_in_von_neumann_mode() {
+        run_generated(20, true);
+        run_generated(64, true); // Fills all of memory, leaving no room for memory operands.
+    }
+
+    #[test]
+    fn program_must_fit_in_memory() {
+        assert!(random_program(0, 1, &MachineConfig::default(), false).is_err());
+        assert!(random_program(65, 1, &MachineConfig::default(), false).is_err());
+    }
+}
@@ -0,0 +1,6 @@
+// Meri library: the assembler and CPU emulator, usable from other Rust code as well as from the `meri` binary.
+pub mod asm; // Assembler from Meri assembly source to program bytes.
+pub mod run; // CPU, instructions, and emulation logic.
+
+pub use asm::{Assembler, MeriError};
+pub use run::{Cpu, CpuState, MachineConfig};
@@ -2,238 +2,625 @@ use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
-mod run; // Import the `run` module which contains CPU, instructions, and emulation logic.
-
-// Import `OperandType` from the `run` module so `lexer` can use it.
-use run::OperandType;
-
-// Local constants for lexer error checking, mirroring the CPU's architecture limits.
-const LEXER_MEMORY_SIZE: usize = 256;
-const LEXER_REGISTER_COUNT: usize = 4;
-
-// Helper function for the lexer to parse register (R#) or memory (M#) operands.
-// It returns the numerical value (index or address) and its corresponding `OperandType`.
-fn parse_reg_mem_operand(operand_str: &str) -> Result<(u8, OperandType), String> {
-    if operand_str.starts_with('R') {
-        // Parse register index
-        let reg_idx = operand_str[1..].parse::<u8>()
-            .map_err(|e| format!("Invalid register index '{}': {}", operand_str, e))?;
-        // Validate register index bounds
-        if reg_idx as usize >= LEXER_REGISTER_COUNT {
-            return Err(format!("Register index {} out of bounds (max {}).", reg_idx, LEXER_REGISTER_COUNT - 1));
-        }
-        Ok((reg_idx, OperandType::Register))
-    } else if operand_str.starts_with('M') {
-        // Parse memory address
-        let mem_addr = operand_str[1..].parse::<u8>()
-            .map_err(|e| format!("Invalid memory address '{}': {}", operand_str, e))?;
-        // Validate memory address bounds
-        if mem_addr as usize >= LEXER_MEMORY_SIZE {
-            return Err(format!("Memory address {} out of bounds (max {}).", mem_addr, LEXER_MEMORY_SIZE - 1));
-        }
-        Ok((mem_addr, OperandType::Memory))
-    } else {
-        // If neither R# nor M# format is found, it's an error for this type of operand.
-        Err(format!("Expected register (R#) or memory (M#) operand, found '{}'.", operand_str))
+use std::process;
+mod equiv; // Import the `equiv` module which compares the behavior of two programs.
+mod generate; // Import the `generate` module which builds random test programs.
+mod reduce; // Import the `reduce` module which shrinks failing programs.
+use meri::run; // The emulator itself lives in the library crate (`lib.rs`).
+use meri::asm::{self, parse_number}; // So does the assembler.
+
+use run::{MachineConfig, OpcodeMap};
+
+// Instructions a program may execute before it is stopped, unless `--max-cycles` says otherwise.
+const DEFAULT_MAX_CYCLES: u64 = 1_000_000;
+
+// Assembles a source file for the machine and opcode map selected on the command line.
+// With `--best-effort`, lines that fail to assemble are reported as warnings on stderr and skipped.
+fn assemble(source: &str, command_line: &CommandLine) -> Result<Vec<u8>, String> {
+    assemble_program(source, command_line).map(|program| program.bytes)
+}
+
+// Like `assemble`, but also returns the `.input` count and labels of the source.
+fn assemble_program(source: &str, command_line: &CommandLine) -> Result<asm::AssembledProgram, String> {
+    let mut skipped = Vec::new();
+    let program = asm::assemble_program(source, &command_line.options.opcode_map, &command_line.options.machine, command_line.options.base, command_line.best_effort.then_some(&mut skipped));
+    for e in skipped {
+        eprintln!("Warning: {} (skipped)", e);
     }
+    program
 }
 
-// The lexer function converts human-readable assembly source code into a byte vector
-// that the Meri CPU emulator can execute.
-// It now handles the new generalized instruction syntax and encodes addressing modes.
-fn lexer(source: String) -> Result<Vec<u8>, String> {
-    let mut program = Vec::new();
-    
-    // Split the source code into individual lines first, and track line numbers
-    for (line_num, line) in source.lines().enumerate() {
-        // Ignore anything after a "//" comment
-        let instruction_part = line.split("//").next().unwrap_or("").trim();
-
-        // Skip empty lines or lines that were entirely comments
-        if instruction_part.is_empty() {
-            continue;
-        }
+// Everything selected on the command line.
+struct CommandLine {
+    file_name: Option<String>,  // Path of the assembly file to run, if any.
+    options: run::RunOptions,   // Options forwarded to the emulator.
+    fenced: bool,               // Only assemble code inside ```meri fenced blocks.
+    gen_random: Option<usize>,  // Generate and run a random program with this many instructions.
+    size_only: bool,            // Report the assembled size instead of running.
+    hexdump: bool,              // Print the assembled bytes next to their disassembly instead of running.
+    disasm: bool,               // Print the assembled program disassembled back into source instead of running.
+    assemble_to: Option<String>, // Write the assembled bytes to this file instead of running.
+    binary: bool,               // The input file holds raw machine code to load as is, not assembly source.
+    reduce: bool,               // Shrink a failing program to a minimal one with the same error instead of running.
+    emit_rust: Option<String>,  // Print the assembled bytes as a Rust constant with this name instead of running.
+    best_effort: bool,          // Skip lines that fail to assemble instead of stopping.
+    equiv: Option<String>,      // Compare the program's behavior against this other source file instead of running.
+    threads: Vec<String>,       // Further source files to run as threads alongside the main program.
+    quantum: usize,             // Instructions each thread runs before the scheduler switches to the next.
+    single_step: bool,          // Run one instruction at a time, waiting for Enter before each.
+}
 
-        // Split the instruction line by semicolon to handle multiple instructions on one line
-        // (though current examples usually have one per line)
-        let parts: Vec<&str> = instruction_part.split(";").collect();
-
-        for part in parts {
-            let trimmed_part = part.trim(); // Remove leading/trailing whitespace
-            if trimmed_part.is_empty() {
-                continue;
-            }
-
-            // Split the instruction line into tokens (opcode and operands).
-            let mut tokens = trimmed_part.split_whitespace();
-            // The first token is expected to be the opcode string.
-            let opcode_str = tokens.next().ok_or_else(|| format!("Line {}: Empty instruction part after semicolon.", line_num + 1))?;
-
-            // Variables to hold the components of the 4-byte instruction.
-            let instruction_bytes: [u8; 4] = match opcode_str {
-                "Mov" | "Add" | "Sub" | "Cmp" => { // Cmp added here
-                    // These instructions expect two operands (destination and source).
-                    let dest_str = tokens.next().ok_or_else(|| format!("Line {}: Missing destination operand for instruction '{}'. Expected format: {} <DEST> <SOURCE>", line_num + 1, opcode_str, opcode_str))?;
-                    let src_str = tokens.next().ok_or_else(|| format!("Line {}: Missing source operand for instruction '{}'. Expected format: {} <DEST> <SOURCE>", line_num + 1, opcode_str, opcode_str))?;
-
-                    // Parse destination and source operands using the helper function.
-                    let (dest_val, dest_type) = parse_reg_mem_operand(dest_str)
-                        .map_err(|e| format!("Line {}: {}", line_num + 1, e))?;
-                    let (src_val, src_type) = parse_reg_mem_operand(src_str)
-                        .map_err(|e| format!("Line {}: {}", line_num + 1, e))?;
-
-                    let mut mode_byte = 0; // Initialize mode byte to 0
-
-                    // Encode addressing modes into the `mode_byte`:
-                    // Bit 0 (0b01) for destination type: 1 if Memory, 0 if Register.
-                    // Bit 1 (0b10) for source type: 1 if Memory, 0 if Register.
-                    if dest_type == OperandType::Memory {
-                        mode_byte |= 0b01;
-                    }
-                    if src_type == OperandType::Memory {
-                        mode_byte |= 0b10;
-                    }
+// Parses a string of hex digit pairs, e.g. `DEADBEEF`, into bytes.
+fn parse_hex_bytes(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(format!("Hex string '{}' has an odd number of digits.", hex));
+    }
+    if let Some(bad) = hex.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(format!("Hex string '{}' contains non-hex character '{}'.", hex, bad));
+    }
+    // All characters are ASCII hex digits here, so slicing by byte index is safe.
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| format!("Invalid hex byte '{}': {}", &hex[i..i + 2], e)))
+        .collect()
+}
+
+// Describes the size of an assembled program for `--size`, e.g. `12 bytes (3 instructions)`.
+fn size_report(program: &[u8]) -> String {
+    format!("{} bytes ({} instructions)", program.len(), program.len() / run::INSTRUCTION_SIZE as usize)
+}
 
-                    // Assign the numerical opcode based on the instruction string.
-                    let opcode_val = match opcode_str {
-                        "Mov" => 0,
-                        "Add" => 2,
-                        "Sub" => 3,
-                        "Cmp" => 6, // Opcode for Cmp
-                        _ => unreachable!(), // This case should theoretically not be reached.
-                    };
-                    [opcode_val, mode_byte, dest_val, src_val]
-                },
-                "MovImm" => {
-                    // MovImm expects a destination (R#/M#) and an immediate value.
-                    let dest_str = tokens.next().ok_or_else(|| format!("Line {}: Missing destination operand for instruction '{}'. Expected format: {} <DEST> <VALUE>", line_num + 1, opcode_str, opcode_str))?;
-                    let value_str = tokens.next().ok_or_else(|| format!("Line {}: Missing immediate value for instruction '{}'. Expected format: {} <DEST> <VALUE>", line_num + 1, opcode_str, opcode_str))?;
-
-                    let (dest_val, dest_type) = parse_reg_mem_operand(dest_str)
-                        .map_err(|e| format!("Line {}: {}", line_num + 1, e))?;
-                    
-                    let immediate_value = value_str.parse::<u8>()
-                        .map_err(|e| format!("Line {}: Invalid immediate value '{}': {}", line_num + 1, value_str, e))?;
-
-                    let mut mode_byte = 0;
-                    // Encode destination type into mode_byte. Source type is irrelevant for MovImm.
-                    if dest_type == OperandType::Memory {
-                        mode_byte |= 0b01;
+// Parses a comma-separated list of byte values, e.g. `1,2,255`.
+fn parse_byte_list(list: &str) -> Result<Vec<u8>, String> {
+    list.split(',')
+        .map(|value| value.trim().parse::<u8>().map_err(|e| format!("Invalid byte value '{}': {}", value, e)))
+        .collect()
+}
+
+// Parses the value following a command line option, e.g. the `N` in `--gen-random N`.
+fn option_value<T: std::str::FromStr>(value: Option<&String>, option: &str) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for option {}.", option))?;
+    value.parse::<T>().map_err(|_| format!("Invalid value '{}' for option {}.", value, option))
+}
+
+// Parses command line arguments. The first argument that isn't an option is the assembly file path.
+fn parse_args(args: &[String]) -> Result<CommandLine, String> {
+    let mut command_line = CommandLine {
+        file_name: None,
+        options: run::RunOptions { max_cycles: Some(DEFAULT_MAX_CYCLES), ..run::RunOptions::default() },
+        fenced: false,
+        gen_random: None,
+        size_only: false,
+        hexdump: false,
+        disasm: false,
+        assemble_to: None,
+        binary: false,
+        reduce: false,
+        emit_rust: None,
+        best_effort: false,
+        equiv: None,
+        threads: Vec::new(),
+        quantum: 1,
+        single_step: false,
+    };
+
+    let mut arg_iter = args.iter().skip(1).peekable(); // Skip the program name.
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--print-state" => command_line.options.print_state = true, // Set flag to print CPU state.
+            "--json" => command_line.options.json = true, // Print the final CPU state as JSON.
+            "--path" => command_line.options.record_path = true, // Record the sequence of executed instructions.
+            "--hot-cfg" => command_line.options.hot_cfg = true, // Print a profiled control-flow graph.
+            "--profile" => command_line.options.profile = true, // Print per-instruction execution counts.
+            "--stats-csv" => command_line.options.stats_csv = Some(option_value(arg_iter.next(), arg)?), // Save them as CSV.
+            "--flamegraph" => command_line.options.flamegraph = Some(option_value(arg_iter.next(), arg)?), // Folded call stacks.
+            "--fenced" => command_line.fenced = true, // Extract assembly from fenced blocks before lexing.
+            "--best-effort" => command_line.best_effort = true, // Warn about and skip lines that don't assemble.
+            "--size" => command_line.size_only = true, // Assemble and report the size without running.
+            "--hexdump" => command_line.hexdump = true, // Assemble and dump bytes with disassembly.
+            "--disasm" => command_line.disasm = true, // Assemble and print the disassembled source.
+            "--binary" => command_line.binary = true, // Load the file as machine code, skipping the lexer.
+            "--max-cycles" => {
+                // 0 turns the execution limit off.
+                let limit: u64 = option_value(arg_iter.next(), arg)?;
+                command_line.options.max_cycles = (limit > 0).then_some(limit);
+            }
+            "--memory-size" => {
+                let memory_size: usize = option_value(arg_iter.next(), arg)?;
+                command_line.options.machine = MachineConfig::new(memory_size, command_line.options.machine.register_count)?;
+            }
+            "--registers" => {
+                let register_count: usize = option_value(arg_iter.next(), arg)?;
+                command_line.options.machine = MachineConfig::new(command_line.options.machine.memory_size, register_count)?;
+            }
+            "--base" => {
+                // Assemble and load the program at this address; labels are relocated to match.
+                let base: u8 = option_value(arg_iter.next(), arg)?;
+                if !base.is_multiple_of(run::INSTRUCTION_SIZE) {
+                    return Err(format!("--base {} is not a multiple of the {}-byte instruction size.", base, run::INSTRUCTION_SIZE));
+                }
+                command_line.options.base = base;
+            }
+            "--reduce" => command_line.reduce = true, // Minimize a program that fails at runtime.
+            "--step" => command_line.single_step = true, // Interactive single-step debugger.
+            "--thread" => command_line.threads.push(option_value(arg_iter.next(), arg)?), // Add a thread.
+            "--quantum" => command_line.quantum = option_value(arg_iter.next(), arg)?, // Thread time slice.
+            "--equiv" => command_line.equiv = Some(option_value(arg_iter.next(), arg)?), // Behavioral comparison.
+            "--assemble" | "-o" => command_line.assemble_to = Some(option_value(arg_iter.next(), arg)?), // Write the binary instead of running.
+            "--emit-rust" => {
+                // Emit the program as `pub const NAME: [u8; N]`; Rust constants are upper case by convention.
+                let name: String = option_value(arg_iter.next(), arg)?;
+                if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(format!("Invalid value '{}' for option {}: expected a Rust identifier.", name, arg));
+                }
+                command_line.emit_rust = Some(name.to_uppercase());
+            }
+            "--report-overflows" => command_line.options.report_overflows = true, // Summarize arithmetic overflows.
+            "--dump-code" => {
+                // Dump program memory after the run: all of it, or `--dump-code <START> <END>` (inclusive).
+                let mut range = (0, u8::MAX);
+                if let Some(start) = arg_iter.next_if(|next| !next.starts_with("--") && next.parse::<u8>().is_ok()) {
+                    range = (option_value(Some(start), arg)?, option_value(arg_iter.next(), arg)?);
+                    if range.0 > range.1 {
+                        return Err(format!("Invalid range {}-{} for option {}: start is after end.", range.0, range.1, arg));
                     }
-                    // Opcode for MovImm
-                    [1, mode_byte, dest_val, immediate_value]
-                },
-                "Inc" | "Dec" => {
-                    // These instructions expect one operand.
-                    let op_str = tokens.next().ok_or_else(|| format!("Line {}: Missing operand for instruction '{}'. Expected format: {} <OPERAND>", line_num + 1, opcode_str, opcode_str))?;
-                    let (op_val, op_type) = parse_reg_mem_operand(op_str)
-                        .map_err(|e| format!("Line {}: {}", line_num + 1, e))?;
-
-                    let mut mode_byte = 0;
-                    // Encode addressing mode for the single operand into the `mode_byte`.
-                    if op_type == OperandType::Memory {
-                        mode_byte |= 0b01; // Only set the destination bit as it's the only operand.
+                }
+                command_line.options.dump_code = Some(range);
+            }
+            "--first-touch" => command_line.options.first_touch = true, // Report each location's first writer.
+            "--trace-diff" => command_line.options.trace_diff = true, // Trace only what each instruction changed.
+            "--trace" => command_line.options.trace = true, // Trace every instruction with its result.
+            "--trace-range" => {
+                // Only trace instructions at PCs from START to END (inclusive).
+                let range: (u8, u8) = (option_value(arg_iter.next(), arg)?, option_value(arg_iter.next(), arg)?);
+                if range.0 > range.1 {
+                    return Err(format!("Invalid range {}-{} for option {}: start is after end.", range.0, range.1, arg));
+                }
+                command_line.options.trace_range = Some(range);
+            }
+            "--ram-hex" => {
+                // Preload RAM from a hex string: `--ram-hex <ADDR> <HEX>`.
+                let address = option_value(arg_iter.next(), arg)?;
+                let hex: String = option_value(arg_iter.next(), arg)?;
+                command_line.options.ram_preload.push((address, parse_hex_bytes(&hex)?));
+            }
+            "--watch" => {
+                // Report every write to a RAM address: `--watch M<ADDR>`.
+                let operand: String = option_value(arg_iter.next(), arg)?;
+                let address = operand.strip_prefix('M').and_then(|address| parse_number(address).ok())
+                    .ok_or_else(|| format!("Invalid value '{}' for option {}: expected a memory address like M5.", operand, arg))?;
+                command_line.options.watch.push(address);
+            }
+            "--budget" => {
+                // Resource limits checked after the run: `--budget code=N data=M`, either part optional.
+                while let Some(limit) = arg_iter.next_if(|next| next.starts_with("code=") || next.starts_with("data=")) {
+                    let (kind, value) = limit.split_once('=').unwrap_or_default();
+                    let bytes = Some(value.parse().map_err(|_| format!("Invalid value '{}' for option {}.", limit, arg))?);
+                    if kind == "code" {
+                        command_line.options.code_budget = bytes;
+                    } else {
+                        command_line.options.data_budget = bytes;
                     }
-
-                    // Assign the numerical opcode.
-                    let opcode_val = match opcode_str {
-                        "Inc" => 4,
-                        "Dec" => 5,
-                        _ => unreachable!(),
-                    };
-                    [opcode_val, mode_byte, op_val, 0] // operand2_val is 0 for single-operand instructions
-                },
-                // New conditional jump instructions
-                "JmpAddr" | "JmpEq" | "JmpNe" | "JmpGt" => { // JmpEq, JmpNe, JmpGt added here
-                    // These instructions expect one numeric address operand.
-                    let addr_str = tokens.next().ok_or_else(|| format!("Line {}: Missing address for instruction '{}'. Expected format: {} <ADDRESS>", line_num + 1, opcode_str, opcode_str))?;
-                    let address_val = addr_str.parse::<u8>()
-                        .map_err(|e| format!("Line {}: Invalid jump address '{}': {}", line_num + 1, addr_str, e))?;
-                    
-                    // mode_byte and operand2_val remain 0 as they are not applicable for jumps.
-                    let opcode_val = match opcode_str {
-                        "JmpAddr" => 7,
-                        "JmpEq" => 8,
-                        "JmpNe" => 9,
-                        "JmpGt" => 10,
-                        _ => unreachable!(),
-                    };
-                    [opcode_val, 0, address_val, 0]
-                },
-                "HLT" => {
-                    // HLT takes no operands. All operand values and mode_byte remain 0.
-                    [11, 0, 0, 0]
-                },
-                _ => return Err(format!("Line {}: Unknown opcode: {}", line_num + 1, opcode_str)), // Error for unrecognized instruction.
-            };
-            
-            // After parsing, check if there are any unexpected extra tokens on the line.
-            if tokens.next().is_some() {
-                return Err(format!("Line {}: Too many operands or unexpected tokens for instruction '{}' on line: '{}'.", line_num + 1, opcode_str, trimmed_part));
-            }
-
-            // Assemble the 4-byte instruction and add it to the program byte vector.
-            program.extend_from_slice(&instruction_bytes);
+                }
+                if command_line.options.code_budget.is_none() && command_line.options.data_budget.is_none() {
+                    return Err(String::from("Option --budget expects code=N and/or data=M."));
+                }
+            }
+            "--borrow-carry" => {
+                // Carry convention for subtraction: `direct` (borrow sets Carry) or `inverted` (no borrow sets Carry).
+                let convention: String = option_value(arg_iter.next(), arg)?;
+                command_line.options.inverted_borrow = match convention.as_str() {
+                    "direct" => false,
+                    "inverted" => true,
+                    _ => return Err(format!("Invalid value '{}' for option {}: expected 'direct' or 'inverted'.", convention, arg)),
+                };
+            }
+            "--von-neumann" => command_line.options.von_neumann = true, // Unify code and data memory.
+            "--detect-stuck" => command_line.options.detect_stuck = true, // Report exact infinite loops.
+            "--stop-at-output" => command_line.options.stop_at_output = true, // Pause after the first output.
+            "--opcode-map" => {
+                // Load a custom opcode numbering used for both assembly and execution.
+                let file_name: String = option_value(arg_iter.next(), arg)?;
+                command_line.options.opcode_map = OpcodeMap::parse(&read_source(&file_name)?)
+                    .map_err(|e| format!("Opcode map {}: {}", file_name, e))?;
+            }
+            "--gen-random" => command_line.gen_random = Some(option_value(arg_iter.next(), arg)?),
+            "--port-in" => {
+                // Queue input bytes on a port: `--port-in <PORT> <BYTES>`.
+                let port = option_value(arg_iter.next(), arg)?;
+                let bytes: String = option_value(arg_iter.next(), arg)?;
+                command_line.options.port_inputs.push((port, parse_byte_list(&bytes)?));
+            }
+            _ if command_line.file_name.is_none() && !arg.starts_with("--") => command_line.file_name = Some(arg.clone()),
+            _ => { /* Ignore unknown options */ }
         }
     }
-    
-    Ok(program) // Return the successfully lexed program as a byte vector.
+    // A trace range on its own traces with `--trace`; it filters `--trace-diff` when that is selected instead.
+    if command_line.options.trace_range.is_some() && !command_line.options.trace_diff {
+        command_line.options.trace = true;
+    }
+    Ok(command_line)
+}
+
+// Reads the assembly source from the given file path.
+fn read_source(file_name: &str) -> Result<String, String> {
+    let path = Path::new(file_name);
+    let display = path.display();
+
+    // Attempt to open the specified assembly file.
+    let mut file = File::open(path).map_err(|why| format!("Couldn't open {}: {}", display, why))?;
+
+    // Attempt to read the file content into a String.
+    let mut source = String::new();
+    file.read_to_string(&mut source).map_err(|why| format!("Couldn't read {}: {}", display, why))?;
+    Ok(source)
+}
+
+// Reads a precompiled program's raw bytes from the given file path. The size must be a whole
+// number of instructions, since a partial instruction can never run.
+fn read_binary(file_name: &str) -> Result<Vec<u8>, String> {
+    let path = Path::new(file_name);
+    let display = path.display();
+
+    // Attempt to open the specified binary file.
+    let mut file = File::open(path).map_err(|why| format!("Couldn't open {}: {}", display, why))?;
+
+    // Attempt to read the file content as bytes; machine code needn't be valid UTF-8.
+    let mut program = Vec::new();
+    file.read_to_end(&mut program).map_err(|why| format!("Couldn't read {}: {}", display, why))?;
+    if !program.len().is_multiple_of(run::INSTRUCTION_SIZE as usize) {
+        return Err(format!(
+            "{} is {} bytes long, which is not a multiple of the {}-byte instruction size.",
+            display, program.len(), run::INSTRUCTION_SIZE
+        ));
+    }
+    Ok(program)
+}
+
+// Writes an assembled program's raw bytes to the given file path, replacing any existing file.
+fn write_binary(file_name: &str, program: &[u8]) -> Result<(), String> {
+    let path = Path::new(file_name);
+    let display = path.display();
+
+    // Attempt to create (or truncate) the output file.
+    let mut file = File::create(path).map_err(|why| format!("Couldn't create {}: {}", display, why))?;
+
+    // Attempt to write the program bytes.
+    file.write_all(program).map_err(|why| format!("Couldn't write {}: {}", display, why))
 }
 
 // Main entry point of the emulator.
 fn main() {
     let args: Vec<String> = env::args().collect(); // Collect command line arguments.
 
-    // Display usage information if not enough arguments are provided.
-    if args.len() < 2 {
-        println!("Meri emulator");
-        println!("Usage:\n {} <file_path> [OPTIONS]", args[0]);
-        println!("OPTIONS:\n --print-state - Print CPU state after program execution");
+    // Print the instruction encoding reference; this needs no program file.
+    if args.iter().skip(1).any(|arg| arg == "--encoding") {
+        print!("{}", run::encoding_reference());
         return;
     }
 
     // Parse command line flags.
-    let mut print_usage: bool = false;
-    if args.len() > 2 {
-        for arg in args.iter().skip(2) { // Skip the program name and file path.
-            match arg.as_str() {
-                "--print-state" => print_usage = true, // Set flag to print CPU state.
-                _ => { /* Ignore unknown options */ }
-            }
+    let mut command_line = match parse_args(&args) {
+        Ok(command_line) => command_line,
+        Err(e) => {
+            eprintln!("Error: {}", e); // Print error to stderr.
+            process::exit(1); // Exit program with a failure status.
         }
-    }
+    };
 
-    // Get the assembly file path from arguments.
-    let file_name = &args[1];
-    let path = Path::new(file_name);
-    let display = path.display();
+    // With `--binary`, the file already holds machine code: load its bytes and skip the lexer.
+    let binary_program = match (&command_line.file_name, command_line.binary) {
+        (Some(file_name), true) if command_line.gen_random.is_none() => match read_binary(file_name) {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                eprintln!("Error: {}", e); // Print error to stderr.
+                process::exit(1); // Exit program with a failure status.
+            }
+        },
+        _ => None,
+    };
 
-    // Attempt to open the specified assembly file.
-    let mut file = match File::open(path) {
-        Err(why) => {
-            eprintln!("Error: Couldn't open {}: {}", display, why); // Print error to stderr.
-            return; // Exit program.
+    // Get the assembly source, either generated or read from the file given in the arguments.
+    let mut source = if binary_program.is_some() {
+        String::new() // Nothing to assemble.
+    } else if let Some(count) = command_line.gen_random {
+        match generate::random_program(
+            count,
+            generate::seed_from_time(),
+            &command_line.options.machine,
+            command_line.options.von_neumann,
+        ) {
+            Ok(generated) => {
+                print!("{}", generated); // Show the generated program so failures can be reproduced.
+                generated
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e); // Print error to stderr.
+                process::exit(1); // Exit program with a failure status.
+            }
         }
-        Ok(file) => file,
+    } else if let Some(file_name) = &command_line.file_name {
+        match read_source(file_name) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Error: {}", e); // Print error to stderr.
+                process::exit(1); // Exit program with a failure status.
+            }
+        }
+    } else {
+        // Display usage information if no program was given.
+        println!("Meri emulator");
+        println!("Usage:\n {} <file_path> [OPTIONS]\n {} --gen-random <N> [OPTIONS]\n {} --encoding", args[0], args[0], args[0]);
+        println!("OPTIONS:\n --print-state - Print CPU state after program execution");
+        println!(" --json - Print the final CPU state as JSON (registers, flags, PC, SP, non-zero RAM cells)");
+        println!(" --path - Print the sequence of executed instructions after program execution");
+        println!(" --hot-cfg - Print a Graphviz control-flow graph annotated with block execution counts");
+        println!(" --profile - Print how many times each instruction executed, most frequent first");
+        println!(" --flamegraph <FILE> - Write instruction counts per Call stack to FILE as folded stacks for flamegraph tools");
+        println!(" --stats-csv <FILE> - Write each executed instruction's count and cycles to FILE as CSV (mnemonic,count,cycles)");
+        println!(" --fenced - Only assemble code inside ```meri fenced blocks, ignoring surrounding prose");
+        println!(" --best-effort - Skip lines that fail to assemble (with a warning) and run the rest of the program");
+        println!(" --report-overflows - Print every arithmetic carry or signed overflow after program execution");
+        println!(" --dump-code [<START> <END>] - Print program memory (optionally only START..=END) as a hex grid after execution");
+        println!(" --first-touch - Print the PC of the first instruction to write each register and RAM address");
+        println!(" --trace - Print each executed instruction to stderr with the new value of its destination");
+        println!(" --trace-range <START> <END> - Only trace instructions at PCs from START to END (inclusive); implies --trace unless --trace-diff is given");
+        println!(" --trace-diff - Print a terse line per instruction showing only the state it changed");
+        println!(" --ram-hex <ADDR> <HEX> - Write bytes given as a hex string (e.g. DEADBEEF) into RAM starting at ADDR");
+        println!(" --watch M<ADDR> - Print the PC, old and new value to stderr whenever the RAM address is written (can be repeated)");
+        println!(" --budget code=<N> data=<M> - After running, check the code size and peak data footprint (bytes) against limits");
+        println!(" --borrow-carry <direct|inverted> - Whether Sub/Dec/Cmp set Carry on a borrow (direct, default) or on no borrow (inverted)");
+        println!(" --von-neumann - Share one memory between code and data, so M# operands can modify the program");
+        println!(" --detect-stuck - Stop with an error when the program repeats an identical state (a true infinite loop)");
+        println!(" --stop-at-output - Stop and print CPU state right after the first output instruction");
+        println!(" --opcode-map <FILE> - Assemble and run with custom opcode numbers (lines of '<MNEMONIC> <OPCODE>')");
+        println!(" --port-in <PORT> <BYTES> - Queue comma-separated input bytes on an I/O port for InP");
+        println!(" --gen-random <N> - Generate, print and run a random valid program of N instructions");
+        println!(" --size - Assemble the program and print its size in bytes and instructions without running it");
+        println!(" --hexdump - Assemble the program and print each instruction's address, hex bytes and disassembly without running it");
+        println!(" --max-cycles <N> - Stop with an error after N executed instructions (default 1000000, 0 for no limit)");
+        println!(" --memory-size <N> - Size in bytes of program memory and of RAM (4-256, default 256)");
+        println!(" --registers <N> - Number of general-purpose registers R0..R<N-1> (1-256, default 4)");
+        println!(" --binary - Treat the input file as raw machine code (e.g. written by --assemble) instead of assembly source");
+        println!(" --assemble <FILE> (or -o <FILE>) - Assemble the program and write its raw bytes to FILE without running it");
+        println!(" --disasm - Assemble the program and print it disassembled back into assembly source without running it");
+        println!(" --base <ADDR> - Assemble the program to be loaded at ADDR (a multiple of 4), relocating its labels, and start it there");
+        println!(" --reduce - Shrink a program that fails at runtime to a minimal one failing with the same error");
+        println!(" --emit-rust <NAME> - Assemble the program and print it as a Rust 'pub const NAME: [u8; N]' without running it");
+        println!(" --thread <FILE> - Also run FILE as a thread sharing RAM with the program (can be repeated)");
+        println!(" --quantum <K> - Instructions each thread runs per turn with --thread (default 1)");
+        println!(" --equiv <FILE> - Run the program and FILE under identical conditions and report whether their final state matches");
+        println!(" --step - Run one instruction at a time, printing the CPU state after each and waiting for Enter (q quits)");
+        println!(" --encoding - Print the 4-byte encoding of every instruction and exit");
+        return;
     };
 
-    // Attempt to read the file content into a String.
-    let mut source = String::new();
-    if let Err(why) = file.read_to_string(&mut source) {
-        eprintln!("Error: Couldn't read {}: {}", display, why); // Print error to stderr.
-        return; // Exit program.
+    // If `--fenced` is set, keep only the assembly inside ```meri blocks.
+    if command_line.fenced && binary_program.is_none() {
+        source = match asm::extract_fenced(&source) {
+            Ok(extracted) => extracted,
+            Err(e) => {
+                eprintln!("Lexer error: {}", e); // Print fence error.
+                process::exit(1); // Exit program with a failure status.
+            }
+        };
     }
 
-    // Lex the source code into an executable program byte vector.
+    // Lex the source code into an executable program byte vector, unless a binary was loaded.
     // Handle potential lexer errors.
-    let program = match lexer(source) {
-        Ok(p) => p, // If successful, get the program bytes.
+    let assembled = binary_program.map_or_else(
+        || assemble_program(&source, &command_line),
+        |bytes| Ok(asm::AssembledProgram { bytes, input_count: None, labels: Vec::new() }),
+    );
+    let program = match assembled {
+        Ok(assembled) => {
+            command_line.options.input_count = assembled.input_count; // Checked against the input before running.
+            command_line.options.labels = assembled.labels; // Names subroutines in `--flamegraph`.
+            assembled.bytes // If successful, get the program bytes.
+        }
         Err(e) => {
             eprintln!("Lexer error: {}", e); // Print lexer error.
-            return; // Exit program.
+            process::exit(1); // Exit program with a failure status.
         }
     };
 
-    // Run the emulation with the lexed program and the print_usage flag.
-    run::run_emulation(program, print_usage);
+    // With `--size`, report how much program memory the assembled code occupies and stop.
+    if command_line.size_only {
+        println!("{}", size_report(&program));
+        return;
+    }
+
+    // With `--hexdump`, print an objdump-style listing of the assembled bytes and stop.
+    if command_line.hexdump {
+        print!("{}", run::hexdump(&program, command_line.options.base, &command_line.options.opcode_map));
+        return;
+    }
+
+    // With `--assemble`, save the assembled bytes to a file and stop.
+    if let Some(out_file) = &command_line.assemble_to {
+        match write_binary(out_file, &program) {
+            Ok(()) => println!("Wrote {} bytes to {}.", program.len(), out_file),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // With `--disasm`, print the program as assembly source reconstructed from its bytes and stop.
+    if command_line.disasm {
+        match run::disassemble(&program, &command_line.options.opcode_map) {
+            Ok(source) => print!("{}", source),
+            Err(e) => {
+                eprintln!("Disassembly error: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // With `--emit-rust`, print the program as a Rust byte array constant and stop.
+    if let Some(name) = &command_line.emit_rust {
+        print!("{}", run::rust_const(&program, name, &command_line.options.opcode_map));
+        return;
+    }
+
+    // With `--equiv`, run both programs and report whether they behave the same.
+    if let Some(other_file) = &command_line.equiv {
+        let other = read_source(other_file)
+            .and_then(|source| if command_line.fenced { asm::extract_fenced(&source) } else { Ok(source) })
+            .and_then(|source| assemble(&source, &command_line))
+            .map_err(|e| format!("{}: {}", other_file, e));
+        match other.and_then(|other| equiv::compare_programs(&program, &other, &command_line.options)) {
+            Ok(differences) if differences.is_empty() => println!("Programs are equivalent."),
+            Ok(differences) => {
+                println!("Programs are not equivalent:");
+                for difference in differences {
+                    println!("  {}", difference);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // With `--reduce`, print a minimal version of the program that still fails the same way.
+    if command_line.reduce {
+        match reduce::reduce_program(&program, &command_line.options) {
+            Ok(reduced) => print!("{}", reduced),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // With `--thread`, assemble the other threads and run them all with the round-robin scheduler.
+    if !command_line.threads.is_empty() {
+        let mut programs = vec![program];
+        for thread_file in &command_line.threads {
+            let thread_program = read_source(thread_file)
+                .and_then(|source| if command_line.fenced { asm::extract_fenced(&source) } else { Ok(source) })
+                .and_then(|source| assemble(&source, &command_line));
+            match thread_program {
+                Ok(thread_program) => programs.push(thread_program),
+                Err(e) => {
+                    eprintln!("Lexer error: {}: {}", thread_file, e);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Err(e) = run::run_multi(programs, command_line.quantum, &command_line.options, &mut std::io::stdin().lock(), &mut std::io::stdout(), &mut std::io::stderr()) {
+            eprintln!("Emulation error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // With `--step`, run the program interactively one instruction at a time.
+    if command_line.single_step {
+        if let Err(e) = run::run_stepping(program, &command_line.options, &mut std::io::stdin().lock(), &mut std::io::stdout(), &mut std::io::stderr()) {
+            eprintln!("Emulation error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Run the emulation with the lexed program and the selected options.
+    if let Err(e) = run::run_emulation(program, &command_line.options, &mut std::io::stdin().lock(), &mut std::io::stdout(), &mut std::io::stderr()) {
+        eprintln!("Emulation error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses a command line given without the program name.
+    fn parse(args: &[&str]) -> Result<CommandLine, String> {
+        let args: Vec<String> = std::iter::once("meri").chain(args.iter().copied()).map(String::from).collect();
+        parse_args(&args)
+    }
+
+    #[test]
+    fn ram_hex_preloads_ram() {
+        let command_line = parse(&["--ram-hex", "0", "0102"]).unwrap();
+        assert_eq!(command_line.options.ram_preload, [(0, vec![1, 2])]);
+        let program = asm::lexer("HLT\n", &OpcodeMap::default(), &MachineConfig::default(), None).unwrap();
+        let outcome = run::run_silently(&program, &command_line.options, 10).unwrap();
+        assert_eq!(&outcome.ram[0..3], &[1, 2, 0]);
+    }
+
+    #[test]
+    fn ram_hex_rejects_malformed_strings() {
+        assert_eq!(parse_hex_bytes("012").unwrap_err(), "Hex string '012' has an odd number of digits.");
+        assert_eq!(parse_hex_bytes("0g").unwrap_err(), "Hex string '0g' contains non-hex character 'g'.");
+        assert_eq!(parse_hex_bytes("DEADbeef").unwrap(), [0xDE, 0xAD, 0xBE, 0xEF]);
+    }
+
+    #[test]
+    fn size_report_counts_four_bytes_per_instruction() {
+        let program = asm::lexer("MovImm R0 1\nInc R0\nHLT\n", &OpcodeMap::default(), &MachineConfig::default(), None).unwrap();
+        assert_eq!(size_report(&program), "12 bytes (3 instructions)");
+    }
+
+    #[test]
+    fn assemble_writes_the_program_bytes_to_the_file() {
+        let command_line = parse(&["prog.asm", "--assemble", "prog.bin"]).unwrap();
+        assert_eq!(command_line.assemble_to.as_deref(), Some("prog.bin"));
+        let program = assemble("MovImm R0 42\nHLT\n", &command_line).unwrap();
+        let path = std::env::temp_dir().join(format!("meri-assemble-{}.bin", std::process::id()));
+        write_binary(path.to_str().unwrap(), &program).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, [1, 0, 0, 42, 0x0B, 0, 0, 0]);
+    }
+
+    #[test]
+    fn binary_loads_what_assemble_wrote() {
+        let command_line = parse(&["prog.bin", "--binary"]).unwrap();
+        assert!(command_line.binary);
+        let program = assemble("MovImm R0 3\nInc R0\nHLT\n", &command_line).unwrap();
+        let path = std::env::temp_dir().join(format!("meri-binary-{}.bin", std::process::id()));
+        let file_name = path.to_str().unwrap();
+        write_binary(file_name, &program).unwrap();
+        let loaded = read_binary(file_name).unwrap();
+        assert_eq!(loaded, program);
+        let outcome = run::run_silently(&loaded, &command_line.options, 10).unwrap();
+        assert_eq!(outcome.registers[0], 4);
+
+        // A partial instruction is rejected before anything runs.
+        write_binary(file_name, &program[..6]).unwrap();
+        let error = read_binary(file_name).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.ends_with("is 6 bytes long, which is not a multiple of the 4-byte instruction size."), "{}", error);
+    }
+
+    #[test]
+    fn registers_option_allows_higher_register_indices() {
+        let command_line = parse(&["--registers", "8"]).unwrap();
+        assert_eq!(command_line.options.machine.register_count, 8);
+        let program = assemble("MovImm R0 9\nMov R7 R0\nHLT\n", &command_line).unwrap();
+        let outcome = run::run_silently(&program, &command_line.options, 10).unwrap();
+        assert_eq!(outcome.registers.len(), 8);
+        assert_eq!(outcome.registers[7], 9);
+        let error = assemble("Mov R8 R0\n", &command_line).unwrap_err();
+        assert!(error.contains("Register index 8 out of bounds (max 7)."), "{}", error);
+    }
+
+    #[test]
+    fn machine_size_options_are_bounds_checked() {
+        let command_line = parse(&["--memory-size", "64"]).unwrap();
+        let error = assemble("Mov R0 M64\n", &command_line).unwrap_err();
+        assert!(error.contains("Memory address 64 out of bounds (max 63)."), "{}", error);
+        assert_eq!(parse(&["--memory-size", "257"]).err().as_deref(), Some("Memory size must be between 4 and 256 bytes, got 257."));
+        assert_eq!(parse(&["--memory-size", "2"]).err().as_deref(), Some("Memory size must be between 4 and 256 bytes, got 2."));
+        assert_eq!(parse(&["--registers", "0"]).err().as_deref(), Some("Register count must be between 1 and 256, got 0."));
+    }
 }
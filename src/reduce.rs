@@ -0,0 +1,100 @@
+use meri::run::{self, RunOptions, INSTRUCTION_SIZE};
+
+// Instructions a candidate may execute before it counts as not reproducing the failure,
+// since removing instructions can turn a failing program into one that never terminates.
+const REDUCE_STEP_LIMIT: u64 = 100_000;
+
+// The part of a runtime error that identifies the failure, without the PC it happened at:
+// removing instructions moves the faulting one to a different address.
+pub fn error_signature(error: &str) -> &str {
+    error.find("PC").map_or(error, |index| &error[..index]).trim_end()
+}
+
+// Runs the given instructions and returns the runtime error they end with, if any.
+fn run_instructions(instructions: &[&[u8]], options: &RunOptions) -> Option<String> {
+    match run::run_silently(&instructions.concat(), options, REDUCE_STEP_LIMIT) {
+        Ok(outcome) => outcome.error,
+        Err(e) => Some(e),
+    }
+}
+
+// Shrinks a program that fails at runtime to a small subset of its instructions that still
+// fails with the same error, by repeatedly trying to delete chunks of instructions, halving
+// the chunk size whenever no chunk can be removed. Returns the reduced program as assembly.
+pub fn reduce_program(program: &[u8], options: &RunOptions) -> Result<String, String> {
+    let original: Vec<&[u8]> = program.chunks(INSTRUCTION_SIZE as usize).collect();
+    let error = match run_instructions(&original, options) {
+        Some(error) if error.starts_with("Execution limit") => {
+            return Err(format!("The program did not finish within {} instructions, so there is no runtime error to reduce.", REDUCE_STEP_LIMIT));
+        }
+        Some(error) => error,
+        None => return Err(String::from("The program runs without error, so there is nothing to reduce.")),
+    };
+    let reproduces = |candidate: &[&[u8]]| {
+        run_instructions(candidate, options).is_some_and(|other| error_signature(&other) == error_signature(&error))
+    };
+
+    let mut current = original.clone();
+    let mut chunk_size = current.len().div_ceil(2).max(1);
+    loop {
+        let mut removed_any = false;
+        let mut start = 0;
+        while start < current.len() {
+            let end = (start + chunk_size).min(current.len());
+            let candidate: Vec<&[u8]> = current[..start].iter().chain(&current[end..]).copied().collect();
+            if !candidate.is_empty() && reproduces(&candidate) {
+                current = candidate; // Keep the deletion and retry the chunk now at `start`.
+                removed_any = true;
+            } else {
+                start += chunk_size;
+            }
+        }
+        if !removed_any {
+            if chunk_size == 1 {
+                break; // No single instruction can be removed any more.
+            }
+            chunk_size = chunk_size.div_ceil(2);
+        }
+    }
+
+    // Jump targets are kept as written, so they may no longer line up with the same instructions.
+    let final_error = run_instructions(&current, options).unwrap_or_default();
+    let mut report = format!(
+        "Reduced to {} of {} instructions, still failing with: {}\n",
+        current.len(), original.len(), final_error
+    );
+    for instruction in &current {
+        report.push_str(&format!("{};\n", run::disassemble_bytes(instruction, &options.opcode_map)));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use meri::asm::lexer;
+    use meri::run::{MachineConfig, OpcodeMap};
+
+    // Assembles source for the default machine.
+    fn assemble(source: &str) -> Vec<u8> {
+        lexer(source, &OpcodeMap::default(), &MachineConfig::default(), None).unwrap()
+    }
+
+    #[test]
+    fn padded_program_reduces_to_the_faulting_instruction() {
+        let program = assemble("Inc R0\nMovImm R1 7\nInc R3\nDiv R0 R2\nInc R1\nAdd R0 R1\nHLT\n");
+        let report = reduce_program(&program, &RunOptions::default()).unwrap();
+        assert_eq!(report, "Reduced to 1 of 7 instructions, still failing with: Runtime error: division by zero. PC: 0\nDiv R0 R2;\n");
+    }
+
+    #[test]
+    fn program_without_an_error_is_not_reduced() {
+        let error = reduce_program(&assemble("Inc R0\nHLT\n"), &RunOptions::default()).unwrap_err();
+        assert_eq!(error, "The program runs without error, so there is nothing to reduce.");
+    }
+
+    #[test]
+    fn error_signature_leaves_out_the_pc() {
+        assert_eq!(error_signature("Runtime error: division by zero. PC: 12"), "Runtime error: division by zero.");
+    }
+}
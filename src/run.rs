@@ -1,29 +1,276 @@
-const MEMORY_SIZE: usize = 256; // Defines the size of both program memory and RAM in bytes.
-const INSTRUCTION_SIZE: u8 = 4; // All instructions are now 4 bytes long.
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufRead, Write};
 
-// Enum to define the type of an operand (Register or Memory).
+const MEMORY_SIZE: usize = 256; // Size of the address space: one-byte addresses reach at most 256 bytes.
+pub const INSTRUCTION_SIZE: u8 = 4; // All instructions are now 4 bytes long.
+const PORT_COUNT: usize = 256; // Number of I/O ports; port numbers are a single operand byte.
+const MAX_RECORDED_PATH: usize = 10_000; // Cap on recorded execution path entries, so long runs don't grow without bound.
+const OUT_CHARACTER: u8 = 0b100; // `Out` mode bit: print the value as an ASCII character instead of a decimal number.
+pub const LOOP_COUNTER: u8 = 3; // Register that `Loop` counts down (R3).
+const THREAD_STACK_SIZE: usize = 16; // Bytes of RAM set aside for each thread's stack with `--thread`.
+pub const DEST_INDIRECT: u8 = 0b1000; // Mode bit: the first operand is the RAM cell addressed by a register (`[R#]`).
+pub const SRC_INDIRECT: u8 = 0b10000; // Mode bit: the second operand is the RAM cell addressed by a register (`[R#]`).
+const MEMORY_OPERAND_CYCLES: u64 = 2; // Extra cycles for each memory operand (`M#` or `[R#]`) on top of `Instructions::cycles`.
+
+// Size parameters of the emulated machine. The assembler and the CPU both take them, so their
+// bounds checks always agree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MachineConfig {
+    pub memory_size: usize,    // Bytes of program memory and of RAM, at most 256 (addresses are one byte).
+    pub register_count: usize, // Number of general-purpose registers R0..R<n-1>, at most 256.
+}
+
+impl MachineConfig {
+    // Checks that both sizes fit in the one-byte operands that address them.
+    pub fn new(memory_size: usize, register_count: usize) -> Result<MachineConfig, String> {
+        if !(INSTRUCTION_SIZE as usize..=MEMORY_SIZE).contains(&memory_size) {
+            return Err(format!("Memory size must be between {} and {} bytes, got {}.", INSTRUCTION_SIZE, MEMORY_SIZE, memory_size));
+        }
+        if !(1..=256).contains(&register_count) {
+            return Err(format!("Register count must be between 1 and 256, got {}.", register_count));
+        }
+        Ok(MachineConfig { memory_size, register_count })
+    }
+}
+
+impl Default for MachineConfig {
+    // The standard Meri machine: 256 bytes each of program memory and RAM, and 4 registers.
+    fn default() -> Self {
+        MachineConfig { memory_size: MEMORY_SIZE, register_count: 4 }
+    }
+}
+
+// Enum to define the type of an operand (Register, Memory or register-indirect).
 // This is used internally by the CPU to know how to interpret operand values.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum OperandType {
     Register, // Operand refers to a CPU register (R0-R3).
     Memory,   // Operand refers to a location in RAM (M0-M255).
+    Indirect, // Operand refers to the location in RAM whose address is held in a register ([R0]).
+}
+
+impl OperandType {
+    // Decodes an operand's type from its memory bit and indirect bit in the mode byte.
+    fn from_mode_byte(mode_byte: u8, memory_bit: u8, indirect_bit: u8) -> OperandType {
+        if mode_byte & indirect_bit != 0 {
+            OperandType::Indirect
+        } else if mode_byte & memory_bit != 0 {
+            OperandType::Memory
+        } else {
+            OperandType::Register
+        }
+    }
+
+    // The mode byte bits that encode this operand type, given the operand's memory bit and indirect bit.
+    pub fn mode_bits(self, memory_bit: u8, indirect_bit: u8) -> u8 {
+        match self {
+            OperandType::Register => 0,
+            OperandType::Memory => memory_bit,
+            OperandType::Indirect => indirect_bit,
+        }
+    }
 }
 
 // Bitmasks for CPU flags
 const FLAG_ZERO: u8 = 0b00000001; // Zero Flag: set if the result of an operation is zero
 const FLAG_CARRY: u8 = 0b00000010; // Carry Flag: set if an arithmetic operation produced a carry/borrow
+const FLAG_SIGN: u8 = 0b00000100; // Sign Flag: set if the result's high bit (bit 7) is set
+const FLAG_OVERFLOW: u8 = 0b00001000; // Overflow Flag: set if a signed operation's result did not fit in an i8
+
+// A host function implementing the `Ext` instruction, called with the CPU and the instruction's
+// three operand bytes. It can read and change the CPU, e.g. with `register` and `set_register`;
+// an error stops the program like any other runtime error.
+pub type ExtensionHandler = fn(&mut Cpu, [u8; 3]) -> Result<(), String>;
 
 // Represents the CPU state.
+// Library users create one with `Cpu::new`, `load` a program and then `step` or `run` it.
 #[derive(Debug)]
-struct CPU {
-    registers: [u8; 4], // 4 general-purpose 8-bit registers (R0-R3).
-    memory: [u8; MEMORY_SIZE], // Program memory, where the loaded instructions reside.
-    ram: [u8; MEMORY_SIZE], // Data memory, separate from program memory, for data manipulation.
+pub struct Cpu {
+    registers: Vec<u8>, // General-purpose 8-bit registers (R0-R3 by default, see `MachineConfig`).
+    memory: Vec<u8>, // Program memory, where the loaded instructions reside.
+    ram: Vec<u8>, // Data memory, separate from program memory, for data manipulation.
     program_counter: u8, // Points to the address of the current instruction in `memory`.
+    program_size: usize, // Length in bytes of the loaded program; execution ends when the PC reaches it.
+    stack_pointer: u8, // Address in RAM of the next free stack slot; Push writes there, then decrements it.
+    stack_base: u8, // Initial stack pointer; the stack is empty when SP is back there.
+    stack_limit: u8, // Stack pointer at which the stack is full: the slot there belongs to something else.
     flags: u8, // 8-bit register to hold status flags (Zero, Carry, etc.)
+    execution_path: Option<Vec<Instructions>>, // Executed instructions in order, if path recording is enabled.
+    pc_counts: Option<Vec<u64>>, // Per-address execution counts, if block profiling (`--hot-cfg`) is enabled.
+    instruction_counts: Option<Vec<u64>>, // Executions of each instruction, indexed by default opcode, if `--profile` or `--stats-csv` is enabled.
+    instruction_cycles: Option<Vec<u64>>, // Cycles spent in each instruction, indexed like `instruction_counts`.
+    overflow_events: Option<Vec<OverflowEvent>>, // Arithmetic overflows seen so far, if `--report-overflows` is enabled.
+    ports: Vec<Port>, // I/O port space, separate from RAM, addressed by `InP`/`OutP`.
+    trace_diff: bool, // Print a terse line of changed state after each instruction (`--trace-diff`).
+    trace: bool, // Print each executed instruction with the value it left in its destination (`--trace`).
+    trace_range: Option<(u8, u8)>, // Inclusive PC range that `--trace` and `--trace-diff` are limited to, if any (`--trace-range`).
+    opcode_map: OpcodeMap, // Opcode byte to instruction mapping used when decoding.
+    stop_at_output: bool, // Pause after the first output-producing instruction (`--stop-at-output`).
+    stuck_detector: Option<StuckDetector>, // Infinite-loop detection state, if `--detect-stuck` is enabled.
+    von_neumann: bool, // Code and data share `memory` instead of the default split `memory`/`ram` (`--von-neumann`).
+    data_high_water: usize, // One past the highest data address the program has written (its data footprint).
+    quiet: bool, // Suppress the "Halted." message and `Dbg` dumps, e.g. while `--reduce` replays candidates.
+    step_limit: Option<u64>, // Stop with an error after this many executed instructions.
+    inverted_borrow: bool, // Subtraction sets Carry when there was *no* borrow (`--borrow-carry inverted`).
+    first_writes: Option<FirstWrites>, // First writer of each location, if `--first-touch` is enabled.
+    pending_output: Vec<u8>, // Text printed by `Out` that hasn't been written to the output stream yet.
+    pending_diagnostics: String, // `Dbg` dumps and `--watch` lines not yet written to the diagnostics stream.
+    input_value: Option<u8>, // Value `step` read from the input stream for the `In` instruction being executed.
+    input_reads: usize, // Number of values `In` has read from the input stream so far.
+    instructions_executed: u64, // Instructions fetched and executed so far; jumps count once like any other.
+    cycles: u64,                // Cycles the executed instructions took, see `instruction_cycles`.
+    watched: Vec<u8>, // RAM addresses whose writes are reported with the diagnostics (`--watch`).
+    extension_handler: Option<ExtensionHandler>, // What `Ext` does, if the host registered a handler.
+    call_stacks: Option<CallStackProfile>, // Instructions executed per call stack, if `--flamegraph` is enabled.
+}
+
+// Instruction counts per call stack for `--flamegraph`. The stack holds the entry addresses of the
+// subroutines entered with `Call` and not yet left with `Ret`, innermost last.
+#[derive(Debug, Clone, Default)]
+struct CallStackProfile {
+    stack: Vec<u8>,
+    counts: BTreeMap<Vec<u8>, u64>, // Instructions executed with each stack, including the Call and Ret themselves.
+}
+
+// The PC of the first instruction to write each register and RAM address, for `--first-touch`.
+#[derive(Debug, Clone)]
+struct FirstWrites {
+    registers: Vec<Option<u8>>,
+    ram: Vec<Option<u8>>,
+}
+
+// Everything that determines how execution continues from a given point.
+// Two equal fingerprints mean the program is in a true infinite loop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StateFingerprint {
+    program_counter: u8,
+    stack_pointer: u8,
+    registers: Vec<u8>,
+    flags: u8,
+    ram: Vec<u8>,
+    pending_input: usize, // Bytes still queued on input ports; reading input changes future behavior.
+    input_reads: usize, // Values read from the input stream, which likewise changes future behavior.
+}
+
+// Detects exact repeats of the machine state using Brent's cycle detection: each state is compared
+// to one saved state, which is replaced at power-of-two intervals. This finds any repeat within a
+// small multiple of the loop length while keeping only a single saved state in memory.
+#[derive(Debug, Clone)]
+struct StuckDetector {
+    saved: Option<StateFingerprint>, // State to compare against.
+    interval: u64,                   // Steps until the saved state is replaced.
+    steps: u64,                      // Steps taken since the saved state was taken.
+}
+
+impl StuckDetector {
+    fn new() -> Self {
+        StuckDetector { saved: None, interval: 1, steps: 0 }
+    }
+
+    // Returns true if the given state exactly repeats the saved one.
+    fn check(&mut self, state: StateFingerprint) -> bool {
+        if self.saved.as_ref() == Some(&state) {
+            return true;
+        }
+        self.steps += 1;
+        if self.saved.is_none() || self.steps >= self.interval {
+            self.saved = Some(state);
+            self.interval *= 2;
+            self.steps = 0;
+        }
+        false
+    }
+}
+
+// An I/O port as bound by the host: bytes queued for the program to read and bytes it has written.
+#[derive(Debug, Clone, Default)]
+struct Port {
+    input: VecDeque<u8>, // Bytes returned by successive `InP` reads, in order.
+    output: Vec<u8>,     // Bytes written by `OutP`, in order.
+}
+
+// An arithmetic instruction that produced an unsigned carry/borrow or a signed overflow.
+#[derive(Debug, Clone)]
+struct OverflowEvent {
+    pc: u8,                    // Address of the overflowing instruction.
+    instruction: Instructions, // The arithmetic instruction that overflowed.
+    operands: (u8, u8),        // Input operand values (destination, source).
+    result: u8,                // The truncated result that was stored.
+    carry: bool,               // Unsigned carry or borrow out of bit 7.
+    signed_overflow: bool,     // Result did not fit when operands are treated as i8.
+}
+
+// Options controlling a single emulation run, set from command-line flags in `main`.
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    pub print_state: bool, // Print the CPU state after execution (`--print-state`).
+    pub json: bool,        // Print the CPU state after execution as JSON instead (`--json`).
+    pub record_path: bool, // Record and print the sequence of executed instructions (`--path`).
+    pub hot_cfg: bool,     // Print a Graphviz control-flow graph annotated with block execution counts (`--hot-cfg`).
+    pub profile: bool,     // Print how often each instruction executed (`--profile`).
+    pub report_overflows: bool, // Print every arithmetic overflow after execution (`--report-overflows`).
+    pub port_inputs: Vec<(u8, Vec<u8>)>, // Bytes queued on I/O ports before the run (`--port-in`).
+    pub trace_diff: bool,  // Print only the state changed by each instruction (`--trace-diff`).
+    pub trace: bool,       // Print every executed instruction and its destination's new value (`--trace`).
+    pub trace_range: Option<(u8, u8)>, // Only trace instructions at PCs in this inclusive range (`--trace-range`).
+    pub opcode_map: OpcodeMap, // Opcode numbering the program was assembled with (`--opcode-map`).
+    pub stop_at_output: bool, // Stop and dump state right after the first output (`--stop-at-output`).
+    pub ram_preload: Vec<(u8, Vec<u8>)>, // Bytes written into RAM at the given addresses before the run (`--ram-hex`).
+    pub detect_stuck: bool, // Stop with an error when the machine state exactly repeats (`--detect-stuck`).
+    pub von_neumann: bool,  // Share one memory between code and data (`--von-neumann`).
+    pub code_budget: Option<usize>, // Maximum assembled program size in bytes (`--budget code=N`).
+    pub data_budget: Option<usize>, // Maximum data footprint in bytes (`--budget data=M`).
+    pub inverted_borrow: bool, // Subtraction sets Carry on no borrow (`--borrow-carry inverted`).
+    pub first_touch: bool,     // Report the first instruction to write each location (`--first-touch`).
+    pub dump_code: Option<(u8, u8)>, // Inclusive range of program memory to print after the run (`--dump-code`).
+    pub machine: MachineConfig, // Memory size and register count (`--memory-size`, `--registers`).
+    pub max_cycles: Option<u64>, // Stop with an error after this many executed instructions (`--max-cycles`).
+    pub watch: Vec<u8>, // RAM addresses whose writes are reported with the diagnostics (`--watch`).
+    pub input_count: Option<usize>, // Number of `In` values the program declared with `.input`, checked before the run.
+    pub base: u8, // Address the program is loaded at and starts from (`--base`).
+    pub stats_csv: Option<String>, // File to write per-instruction execution statistics to as CSV (`--stats-csv`).
+    pub extension_handler: Option<ExtensionHandler>, // Host function run by the `Ext` instruction (library use only).
+    pub flamegraph: Option<String>, // File to write instruction counts per call stack to as folded stacks (`--flamegraph`).
+    pub labels: Vec<(String, u8)>, // Label names and addresses from the source, used to name subroutines in reports.
 }
 
-impl CPU {
+impl Cpu {
+    // The array memory operands address: the separate `ram` by default (Harvard architecture),
+    // or the program `memory` itself in von Neumann mode, where code and data share one address space.
+    fn data_memory(&self) -> &[u8] {
+        if self.von_neumann { &self.memory } else { &self.ram }
+    }
+
+    // Mutable counterpart of `data_memory`.
+    fn data_memory_mut(&mut self) -> &mut [u8] {
+        if self.von_neumann { &mut self.memory } else { &mut self.ram }
+    }
+
+    // Whether the instruction at `pc` is traced: with `--trace-range`, only those inside the range are.
+    fn in_trace_range(&self, pc: u8) -> bool {
+        self.trace_range.is_none_or(|(start, end)| (start..=end).contains(&pc))
+    }
+
+    // Address of the instruction after the current one. The PC is a single byte, so there is
+    // no next instruction after the last one that fits in memory.
+    fn next_instruction_address(&self) -> Result<u8, String> {
+        self.program_counter.checked_add(INSTRUCTION_SIZE).ok_or_else(|| {
+            format!("Runtime error: Program counter overflow: no instruction can follow the end of the {}-byte address space. PC: {}", MEMORY_SIZE, self.program_counter)
+        })
+    }
+
+    // Moves the PC to a jump target, which must be the start of an instruction inside the loaded program.
+    fn jump_to(&mut self, target: u8) -> Result<(), String> {
+        if target as usize >= self.program_size {
+            return Err(format!("Runtime error: Jump target {} is outside the {}-byte program. PC: {}", target, self.program_size, self.program_counter));
+        }
+        if !target.is_multiple_of(INSTRUCTION_SIZE) {
+            return Err(format!("Runtime error: Jump target {} is not a multiple of the {}-byte instruction size. PC: {}", target, INSTRUCTION_SIZE, self.program_counter));
+        }
+        self.program_counter = target;
+        Ok(())
+    }
+
     // Helper to set a specific flag
     fn set_flag(&mut self, flag: u8) {
         self.flags |= flag;
@@ -39,7 +286,55 @@ impl CPU {
         (self.flags & flag) != 0
     }
 
-    // Update Zero and Carry flags based on an operation's result and carry_out status
+    // Helper to set or clear a specific flag depending on a condition
+    fn assign_flag(&mut self, flag: u8, condition: bool) {
+        if condition {
+            self.set_flag(flag);
+        } else {
+            self.clear_flag(flag);
+        }
+    }
+
+    // Update Zero, Sign and Overflow flags after a signed operation.
+    // Carry is about unsigned results, which a signed operation doesn't have, so it is left alone.
+    fn update_signed_flags(&mut self, result: u8, overflow: bool) {
+        self.assign_flag(FLAG_ZERO, result == 0);
+        self.assign_flag(FLAG_SIGN, (result & 0x80) != 0);
+        self.assign_flag(FLAG_OVERFLOW, overflow);
+    }
+
+    // Records an arithmetic overflow event if overflow reporting is enabled and anything overflowed.
+    fn log_overflow(&mut self, instruction: Instructions, operands: (u8, u8), result: u8, carry: bool, signed_overflow: bool) {
+        if !carry && !signed_overflow {
+            return;
+        }
+        let pc = self.program_counter;
+        if let Some(events) = self.overflow_events.as_mut() {
+            events.push(OverflowEvent { pc, instruction, operands, result, carry, signed_overflow });
+        }
+    }
+
+    // Carry Flag value for a subtraction that did or didn't borrow, following the `--borrow-carry`
+    // convention: "direct" (default) sets Carry on a borrow, "inverted" sets it when there was none.
+    fn borrow_to_carry(&self, borrow: bool) -> bool {
+        borrow != self.inverted_borrow
+    }
+
+    // Whether the flags from the last comparison mean "unsigned greater than": the result was not
+    // zero and the subtraction did not borrow, however the borrow is represented in Carry.
+    fn is_unsigned_greater(&self) -> bool {
+        let borrowed = self.is_flag_set(FLAG_CARRY) != self.inverted_borrow;
+        !self.is_flag_set(FLAG_ZERO) && !borrowed
+    }
+
+    // Whether the flags from the last comparison mean "signed less than": Sign differs from
+    // Overflow, i.e. the i8 subtraction result was negative unless it overflowed.
+    fn is_signed_less(&self) -> bool {
+        self.is_flag_set(FLAG_SIGN) != self.is_flag_set(FLAG_OVERFLOW)
+    }
+
+    // Update Zero, Carry and Sign flags based on an operation's result and carry_out status.
+    // Overflow is cleared; arithmetic that can overflow as i8 uses `update_arithmetic_flags` instead.
     fn update_flags(&mut self, result: u8, carry_out: bool) {
         if result == 0 {
             self.set_flag(FLAG_ZERO);
@@ -52,6 +347,16 @@ impl CPU {
         } else {
             self.clear_flag(FLAG_CARRY);
         }
+
+        self.assign_flag(FLAG_SIGN, (result & 0x80) != 0);
+        self.clear_flag(FLAG_OVERFLOW);
+    }
+
+    // Like `update_flags`, but also records whether the operation overflowed as a signed i8,
+    // for add/subtract style instructions whose flags feed the signed jumps.
+    fn update_arithmetic_flags(&mut self, result: u8, carry_out: bool, signed_overflow: bool) {
+        self.update_flags(result, carry_out);
+        self.assign_flag(FLAG_OVERFLOW, signed_overflow);
     }
 }
 
@@ -59,6 +364,7 @@ impl CPU {
 // Enum for the generalized instructions.
 // This is a reduced set compared to the previous version, as operations
 // now handle different operand types (Reg/Mem) via the `mode_byte`.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Instructions {
     Mov,       // General purpose move: Moves data between Reg/Reg, Reg/Mem, Mem/Reg.
@@ -71,13 +377,561 @@ pub enum Instructions {
     JmpAddr,   // Jump to address: Sets the program counter to a specific address unconditionally.
     JmpEq,     // Jump if Equal: Jumps if Zero Flag is set.
     JmpNe,     // Jump if Not Equal: Jumps if Zero Flag is clear.
-    JmpGt,     // Jump if Greater Than: Jumps if Zero Flag is clear AND Carry Flag is clear (for unsigned).
+    JmpGt,     // Jump if Greater Than: Jumps if Zero Flag is clear AND there was no borrow (for unsigned).
     HLT,       // Halt execution: Stops the CPU.
+    Cas,       // Compare-and-swap: Writes a new register value to RAM if it holds the expected register value.
+    IMul,      // Signed multiply: Multiplies two operands as i8, storing the truncated product.
+    IDiv,      // Signed divide: Divides two operands as i8, storing the quotient (truncated toward zero).
+    Clr,       // Clear: Sets a Reg or Mem location to 0.
+    Dbg,       // Debug dump: Prints the current CPU state to stderr and continues.
+    InP,       // Port input: Reads the next byte from an I/O port into a Reg or Mem location.
+    OutP,      // Port output: Writes a Reg or Mem value to an I/O port.
+    SetEq,     // Set if Equal: Writes 1 to a Reg or Mem location if Zero Flag is set, else 0.
+    SetNe,     // Set if Not Equal: Writes 1 if Zero Flag is clear, else 0.
+    SetGt,     // Set if Greater Than: Writes 1 if Zero Flag is clear and there was no borrow (unsigned), else 0.
+    Rol16,     // Rotate Left 16-bit: Rotates a register pair (R0:R1 or R2:R3, high byte first) left by one bit.
+    Ror16,     // Rotate Right 16-bit: Rotates a register pair right by one bit.
+    Abs,       // Absolute value: Replaces a Reg or Mem location (as i8) with its magnitude; -128 stays -128 and sets Overflow.
+    Mul,       // Unsigned multiply: Multiplies two operands as u8, storing the truncated product; Carry is set if it exceeded 255.
+    Div,       // Unsigned divide: Divides two operands as u8, storing the quotient; dividing by zero is a runtime error.
+    Mod,       // Unsigned modulo: Divides two operands as u8, storing the remainder; dividing by zero is a runtime error.
+    And,       // Bitwise AND: Combines two operands bit by bit, storing the result in the destination.
+    Or,        // Bitwise OR: Combines two operands bit by bit, storing the result in the destination.
+    Xor,       // Bitwise XOR: Combines two operands bit by bit, storing the result in the destination.
+    Not,       // Bitwise NOT: Inverts every bit of a Reg or Mem location (one's complement).
+    Shl,       // Shift left: Shifts the destination left by the source value (masked to 0-7); Carry gets the last bit shifted out.
+    Shr,       // Shift right: Shifts the destination right by the source value (masked to 0-7); Carry gets the last bit shifted out.
+    AddImm,    // Add Immediate: Adds a constant value to a Reg or Mem location.
+    SubImm,    // Subtract Immediate: Subtracts a constant value from a Reg or Mem location.
+    CmpImm,    // Compare Immediate: Compares a Reg or Mem location against a constant and sets flags like Cmp.
+    Push,      // Push: Writes a Reg or Mem value to the stack slot at SP, then decrements SP.
+    Pop,       // Pop: Increments SP, then reads the stack slot at SP into a Reg or Mem location.
+    Call,      // Call subroutine: Pushes the address of the next instruction onto the stack and jumps to an address.
+    Ret,       // Return from subroutine: Pops an address off the stack into the program counter.
+    JmpLt,     // Jump if Less Than (signed): Jumps if Sign Flag differs from Overflow Flag.
+    JmpGe,     // Jump if Greater or Equal (signed): Jumps if Sign Flag equals Overflow Flag.
+    JmpLe,     // Jump if Less or Equal (signed): Jumps if Zero Flag is set or Sign Flag differs from Overflow Flag.
+    Out,       // Output: Prints the operand's value as a decimal number on its own line, or as an ASCII character.
+    In,        // Input: Reads a number from the input stream into a register or memory.
+    Xchg,      // Exchange: Swaps the values of two operands (Reg/Reg, Reg/Mem, Mem/Reg) without touching flags.
+    JmpC,      // Jump if Carry: Jumps if Carry Flag is set, e.g. after an Add that overflowed 255.
+    JmpNc,     // Jump if No Carry: Jumps if Carry Flag is clear.
+    JmpRel,    // Relative jump: Adds a signed offset to the address of the jump itself, for position-independent code.
+    Test,      // Bit test: Sets flags from the bitwise AND of two operands like And, but discards the result.
+    Adc,       // Add with carry: Adds two operands plus the Carry Flag, for the higher bytes of multi-byte additions.
+    Sbb,       // Subtract with borrow: Subtracts the source and the borrow held in the Carry Flag, for multi-byte subtractions.
+    Loop,      // Counted loop: Decrements R3 and jumps to an address unless it reached zero.
+    Neg,       // Negate: Replaces a Reg or Mem location with its two's complement (0 - value); Carry is set unless it was 0.
+    Ext,       // Extension: Runs the handler the host registered (see `ExtensionHandler`) with the three operand bytes.
+}
+
+// Describes which operands an instruction takes and how they are laid out in its 4 bytes.
+// This metadata drives both the lexer and the `--encoding` reference, so the two cannot drift apart.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum OperandFormat {
+    DestSrc,     // <DEST> <SOURCE>: two Reg/Mem operands, both types encoded in the mode byte.
+    DestImm,     // <DEST> <VALUE>: a Reg/Mem destination and an immediate value.
+    Single,      // <OPERAND>: a single Reg/Mem operand.
+    Address,     // <ADDRESS>: a jump target address, no mode byte.
+    Offset,      // <OFFSET>: a signed (i8) jump distance from the instruction's own address, no mode byte.
+    CompareSwap, // <ADDR> <EXPECTED_REG> <NEW_REG>: fixed operand types, the mode byte holds the address.
+    DestPort,    // <DEST> <PORT>: a Reg/Mem destination and an I/O port number.
+    PortSrc,     // <PORT> <SRC>: an I/O port number and a Reg/Mem source.
+    RegisterPair, // <PAIR>: R0 (for R0:R1) or R2 (for R2:R3), used as one 16-bit value.
+    Output,      // <SRC> [char]: a Reg/Mem source, optionally printed as a character.
+    RawBytes,    // <BYTE> <BYTE> <BYTE>: three bytes passed on as they are, the first in the mode byte.
+    NoOperands,  // No operands at all.
+}
+
+impl OperandFormat {
+    // Operand syntax as written in assembly, used in lexer error messages and the encoding reference.
+    pub fn syntax(self) -> &'static str {
+        match self {
+            OperandFormat::DestSrc => "<DEST> <SOURCE>",
+            OperandFormat::DestImm => "<DEST> <VALUE>",
+            OperandFormat::Single => "<OPERAND>",
+            OperandFormat::Address => "<ADDRESS>",
+            OperandFormat::Offset => "<OFFSET>",
+            OperandFormat::CompareSwap => "<ADDR> <EXPECTED_REG> <NEW_REG>",
+            OperandFormat::DestPort => "<DEST> <PORT>",
+            OperandFormat::PortSrc => "<PORT> <SRC>",
+            OperandFormat::RegisterPair => "<PAIR>",
+            OperandFormat::Output => "<SRC> [char]",
+            OperandFormat::RawBytes => "<BYTE> <BYTE> <BYTE>",
+            OperandFormat::NoOperands => "",
+        }
+    }
+
+    // Mode byte bits an instruction using this format may set; any other set bit makes the
+    // instruction invalid. Ext passes the whole mode byte on to its handler, so any value is allowed
+    // there. Cas uses it as its RAM address instead of mode bits: its operand types are fixed, so no
+    // bit selects a type, and the address is bounds-checked like any `M#` operand when Cas runs,
+    // which makes every value safe to accept.
+    fn allowed_mode_bits(self) -> u8 {
+        match self {
+            OperandFormat::DestSrc => 0b11 | DEST_INDIRECT | SRC_INDIRECT,
+            OperandFormat::DestImm | OperandFormat::Single | OperandFormat::DestPort => 0b01 | DEST_INDIRECT,
+            OperandFormat::PortSrc => 0b10 | SRC_INDIRECT,
+            OperandFormat::Output => 0b01 | DEST_INDIRECT | OUT_CHARACTER,
+            OperandFormat::CompareSwap | OperandFormat::RawBytes => 0xFF,
+            OperandFormat::Address | OperandFormat::Offset | OperandFormat::RegisterPair | OperandFormat::NoOperands => 0,
+        }
+    }
+
+    // Whether an instruction using this format may have the given mode byte: it sets only allowed
+    // bits, and no operand is marked as both memory and indirect.
+    fn accepts_mode_byte(self, mode_byte: u8) -> bool {
+        let both = |memory_bit: u8, indirect_bit: u8| mode_byte & memory_bit != 0 && mode_byte & indirect_bit != 0;
+        let conflicting = self.allowed_mode_bits() != 0xFF && (both(0b01, DEST_INDIRECT) || both(0b10, SRC_INDIRECT));
+        mode_byte & !self.allowed_mode_bits() == 0 && !conflicting
+    }
+
+    // Meaning of bytes 1, 2 and 3 of an instruction using this format (byte 0 is always the opcode).
+    fn byte_descriptions(self) -> [&'static str; 3] {
+        match self {
+            OperandFormat::DestSrc => [
+                "mode byte (bit 0/3 = destination is memory/[register], bit 1/4 = source is memory/[register])",
+                "destination register index or memory address",
+                "source register index or memory address",
+            ],
+            OperandFormat::DestImm => [
+                "mode byte (bit 0/3 = destination is memory/[register])",
+                "destination register index or memory address",
+                "immediate value",
+            ],
+            OperandFormat::Single => [
+                "mode byte (bit 0/3 = operand is memory/[register])",
+                "operand register index or memory address",
+                "unused (0)",
+            ],
+            OperandFormat::Address => [
+                "unused (0)",
+                "target address",
+                "unused (0)",
+            ],
+            OperandFormat::Offset => [
+                "unused (0)",
+                "signed offset from this instruction's address (two's complement)",
+                "unused (0)",
+            ],
+            OperandFormat::CompareSwap => [
+                "memory address",
+                "expected-value register index",
+                "new-value register index",
+            ],
+            OperandFormat::DestPort => [
+                "mode byte (bit 0/3 = destination is memory/[register])",
+                "destination register index or memory address",
+                "port number",
+            ],
+            OperandFormat::PortSrc => [
+                "mode byte (bit 1/4 = source is memory/[register])",
+                "port number",
+                "source register index or memory address",
+            ],
+            OperandFormat::RegisterPair => [
+                "unused (0)",
+                "high register index of the pair (0 = R0:R1, 2 = R2:R3)",
+                "unused (0)",
+            ],
+            OperandFormat::Output => [
+                "mode byte (bit 0/3 = source is memory/[register], bit 2 = print as an ASCII character)",
+                "source register index or memory address",
+                "unused (0)",
+            ],
+            OperandFormat::RawBytes => [
+                "first byte for the extension handler",
+                "second byte for the extension handler",
+                "third byte for the extension handler",
+            ],
+            OperandFormat::NoOperands => [
+                "unused (0)",
+                "unused (0)",
+                "unused (0)",
+            ],
+        }
+    }
+}
+
+impl Instructions {
+    // Every instruction, in opcode order.
+    pub const ALL: [Instructions; 56] = [
+        Instructions::Mov,
+        Instructions::MovImm,
+        Instructions::Add,
+        Instructions::Sub,
+        Instructions::Inc,
+        Instructions::Dec,
+        Instructions::Cmp,
+        Instructions::JmpAddr,
+        Instructions::JmpEq,
+        Instructions::JmpNe,
+        Instructions::JmpGt,
+        Instructions::HLT,
+        Instructions::Cas,
+        Instructions::IMul,
+        Instructions::IDiv,
+        Instructions::Clr,
+        Instructions::Dbg,
+        Instructions::InP,
+        Instructions::OutP,
+        Instructions::SetEq,
+        Instructions::SetNe,
+        Instructions::SetGt,
+        Instructions::Rol16,
+        Instructions::Ror16,
+        Instructions::Abs,
+        Instructions::Mul,
+        Instructions::Div,
+        Instructions::Mod,
+        Instructions::And,
+        Instructions::Or,
+        Instructions::Xor,
+        Instructions::Not,
+        Instructions::Shl,
+        Instructions::Shr,
+        Instructions::AddImm,
+        Instructions::SubImm,
+        Instructions::CmpImm,
+        Instructions::Push,
+        Instructions::Pop,
+        Instructions::Call,
+        Instructions::Ret,
+        Instructions::JmpLt,
+        Instructions::JmpGe,
+        Instructions::JmpLe,
+        Instructions::Out,
+        Instructions::In,
+        Instructions::Xchg,
+        Instructions::JmpC,
+        Instructions::JmpNc,
+        Instructions::JmpRel,
+        Instructions::Test,
+        Instructions::Adc,
+        Instructions::Sbb,
+        Instructions::Loop,
+        Instructions::Neg,
+        Instructions::Ext,
+    ];
+
+    // The assembly mnemonic for this instruction.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Instructions::Mov => "Mov",
+            Instructions::MovImm => "MovImm",
+            Instructions::Add => "Add",
+            Instructions::Sub => "Sub",
+            Instructions::Inc => "Inc",
+            Instructions::Dec => "Dec",
+            Instructions::Cmp => "Cmp",
+            Instructions::JmpAddr => "JmpAddr",
+            Instructions::JmpEq => "JmpEq",
+            Instructions::JmpNe => "JmpNe",
+            Instructions::JmpGt => "JmpGt",
+            Instructions::HLT => "HLT",
+            Instructions::Cas => "Cas",
+            Instructions::IMul => "IMul",
+            Instructions::IDiv => "IDiv",
+            Instructions::Clr => "Clr",
+            Instructions::Dbg => "Dbg",
+            Instructions::InP => "InP",
+            Instructions::OutP => "OutP",
+            Instructions::SetEq => "SetEq",
+            Instructions::SetNe => "SetNe",
+            Instructions::SetGt => "SetGt",
+            Instructions::Rol16 => "Rol16",
+            Instructions::Ror16 => "Ror16",
+            Instructions::Abs => "Abs",
+            Instructions::Mul => "Mul",
+            Instructions::Div => "Div",
+            Instructions::Mod => "Mod",
+            Instructions::And => "And",
+            Instructions::Or => "Or",
+            Instructions::Xor => "Xor",
+            Instructions::Not => "Not",
+            Instructions::Shl => "Shl",
+            Instructions::Shr => "Shr",
+            Instructions::AddImm => "AddImm",
+            Instructions::SubImm => "SubImm",
+            Instructions::CmpImm => "CmpImm",
+            Instructions::Push => "Push",
+            Instructions::Pop => "Pop",
+            Instructions::Call => "Call",
+            Instructions::Ret => "Ret",
+            Instructions::JmpLt => "JmpLt",
+            Instructions::JmpGe => "JmpGe",
+            Instructions::JmpLe => "JmpLe",
+            Instructions::Out => "Out",
+            Instructions::In => "In",
+            Instructions::Xchg => "Xchg",
+            Instructions::JmpC => "JmpC",
+            Instructions::JmpNc => "JmpNc",
+            Instructions::JmpRel => "JmpRel",
+            Instructions::Test => "Test",
+            Instructions::Adc => "Adc",
+            Instructions::Sbb => "Sbb",
+            Instructions::Loop => "Loop",
+            Instructions::Neg => "Neg",
+            Instructions::Ext => "Ext",
+        }
+    }
+
+    // Looks up an instruction by its assembly mnemonic, ignoring case (`MOV`, `mov` and `Mov` all match).
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Instructions> {
+        Instructions::ALL.iter().copied().find(|instruction| instruction.mnemonic().eq_ignore_ascii_case(mnemonic))
+    }
+
+    // The built-in opcode byte this instruction is encoded with, as used by the default `OpcodeMap`.
+    pub fn opcode(self) -> u8 {
+        match self {
+            Instructions::Mov => 0,
+            Instructions::MovImm => 1,
+            Instructions::Add => 2,
+            Instructions::Sub => 3,
+            Instructions::Inc => 4,
+            Instructions::Dec => 5,
+            Instructions::Cmp => 6,
+            Instructions::JmpAddr => 7,
+            Instructions::JmpEq => 8,
+            Instructions::JmpNe => 9,
+            Instructions::JmpGt => 10,
+            Instructions::HLT => 11,
+            Instructions::Cas => 12,
+            Instructions::IMul => 13,
+            Instructions::IDiv => 14,
+            Instructions::Clr => 15,
+            Instructions::Dbg => 16,
+            Instructions::InP => 17,
+            Instructions::OutP => 18,
+            Instructions::SetEq => 19,
+            Instructions::SetNe => 20,
+            Instructions::SetGt => 21,
+            Instructions::Rol16 => 22,
+            Instructions::Ror16 => 23,
+            Instructions::Abs => 24,
+            Instructions::Mul => 25,
+            Instructions::Div => 26,
+            Instructions::Mod => 27,
+            Instructions::And => 28,
+            Instructions::Or => 29,
+            Instructions::Xor => 30,
+            Instructions::Not => 31,
+            Instructions::Shl => 32,
+            Instructions::Shr => 33,
+            Instructions::AddImm => 34,
+            Instructions::SubImm => 35,
+            Instructions::CmpImm => 36,
+            Instructions::Push => 37,
+            Instructions::Pop => 38,
+            Instructions::Call => 39,
+            Instructions::Ret => 40,
+            Instructions::JmpLt => 41,
+            Instructions::JmpGe => 42,
+            Instructions::JmpLe => 43,
+            Instructions::Out => 44,
+            Instructions::In => 45,
+            Instructions::Xchg => 46,
+            Instructions::JmpC => 47,
+            Instructions::JmpNc => 48,
+            Instructions::JmpRel => 49,
+            Instructions::Test => 50,
+            Instructions::Adc => 51,
+            Instructions::Sbb => 52,
+            Instructions::Loop => 53,
+            Instructions::Neg => 54,
+            Instructions::Ext => 55,
+        }
+    }
+
+    // Whether executing this instruction produces program output (used by `--stop-at-output`).
+    pub fn produces_output(self) -> bool {
+        matches!(self, Instructions::OutP | Instructions::Out)
+    }
+
+    // Base cost in cycles, with all operands in registers. Memory operands add
+    // `MEMORY_OPERAND_CYCLES` each (see `instruction_cycles`).
+    pub fn cycles(self) -> u64 {
+        match self {
+            Instructions::Mul | Instructions::IMul => 3,
+            Instructions::Div | Instructions::IDiv | Instructions::Mod => 6,
+            // The stack lives in RAM, so these pay for one memory access.
+            Instructions::Push | Instructions::Pop | Instructions::Call | Instructions::Ret => 1 + MEMORY_OPERAND_CYCLES,
+            _ => 1,
+        }
+    }
+
+    // How this instruction's operands are written and encoded.
+    pub fn operand_format(self) -> OperandFormat {
+        match self {
+            Instructions::Mov | Instructions::Add | Instructions::Sub | Instructions::Cmp
+            | Instructions::IMul | Instructions::IDiv | Instructions::Mul | Instructions::Div
+            | Instructions::Mod | Instructions::And | Instructions::Or | Instructions::Xor
+            | Instructions::Shl | Instructions::Shr | Instructions::Xchg | Instructions::Test
+            | Instructions::Adc | Instructions::Sbb => OperandFormat::DestSrc,
+            Instructions::MovImm | Instructions::AddImm | Instructions::SubImm | Instructions::CmpImm => OperandFormat::DestImm,
+            Instructions::Inc | Instructions::Dec | Instructions::Clr
+            | Instructions::SetEq | Instructions::SetNe | Instructions::SetGt | Instructions::Abs
+            | Instructions::Not | Instructions::Push | Instructions::Pop | Instructions::In
+            | Instructions::Neg => OperandFormat::Single,
+            Instructions::JmpAddr | Instructions::JmpEq | Instructions::JmpNe | Instructions::JmpGt
+            | Instructions::Call | Instructions::JmpLt | Instructions::JmpGe | Instructions::JmpLe
+            | Instructions::JmpC | Instructions::JmpNc | Instructions::Loop => OperandFormat::Address,
+            Instructions::JmpRel => OperandFormat::Offset,
+            Instructions::Cas => OperandFormat::CompareSwap,
+            Instructions::InP => OperandFormat::DestPort,
+            Instructions::OutP => OperandFormat::PortSrc,
+            Instructions::Rol16 | Instructions::Ror16 => OperandFormat::RegisterPair,
+            Instructions::Out => OperandFormat::Output,
+            Instructions::Ext => OperandFormat::RawBytes,
+            Instructions::HLT | Instructions::Dbg | Instructions::Ret => OperandFormat::NoOperands,
+        }
+    }
+}
+
+// Maps opcode bytes to instructions and back. The default map uses each instruction's built-in
+// opcode (see `Instructions::opcode`), so opcodes are defined in one place; `--opcode-map` can load
+// a custom one for encoding experiments.
+// The same map must be used to assemble and to run a program.
+#[derive(Debug, Clone)]
+pub struct OpcodeMap {
+    opcodes: Vec<u8>, // Opcode byte of each instruction, indexed like `Instructions::ALL`.
+}
+
+impl Default for OpcodeMap {
+    fn default() -> Self {
+        OpcodeMap {
+            opcodes: Instructions::ALL.iter().map(|instruction| instruction.opcode()).collect(),
+        }
+    }
+}
+
+impl OpcodeMap {
+    // Parses an opcode map file. Each non-empty line is `<MNEMONIC> <OPCODE>`, overriding that
+    // instruction's default opcode. Mnemonics and comments (`//` or `#`) follow the assembler's rules,
+    // so mnemonics are not case-sensitive. Every opcode must stay unique.
+    pub fn parse(text: &str) -> Result<OpcodeMap, String> {
+        let mut map = OpcodeMap::default();
+        for (line_num, line) in text.lines().enumerate() {
+            let entry = crate::asm::strip_comment(line).trim();
+            if entry.is_empty() {
+                continue;
+            }
+
+            let mut tokens = entry.split_whitespace();
+            let (mnemonic, opcode_str) = match (tokens.next(), tokens.next(), tokens.next()) {
+                (Some(mnemonic), Some(opcode_str), None) => (mnemonic, opcode_str),
+                _ => return Err(format!("Line {}: Expected format: <MNEMONIC> <OPCODE>", line_num + 1)),
+            };
+            let instruction = Instructions::from_mnemonic(mnemonic)
+                .ok_or_else(|| format!("Line {}: Unknown instruction: {}", line_num + 1, mnemonic))?;
+            let index = Instructions::ALL.iter().position(|&other| other == instruction).unwrap_or(0);
+            map.opcodes[index] = opcode_str.parse::<u8>()
+                .map_err(|e| format!("Line {}: Invalid opcode '{}': {}", line_num + 1, opcode_str, e))?;
+        }
+
+        // Two instructions sharing an opcode could not be told apart when decoding.
+        for (i, &opcode) in map.opcodes.iter().enumerate() {
+            if let Some(j) = map.opcodes[i + 1..].iter().position(|&other| other == opcode) {
+                return Err(format!(
+                    "Opcode {} is assigned to both {} and {}.",
+                    opcode, Instructions::ALL[i].mnemonic(), Instructions::ALL[i + 1 + j].mnemonic()
+                ));
+            }
+        }
+        Ok(map)
+    }
+
+    // The opcode byte this map assigns to an instruction.
+    pub fn encode(&self, instruction: Instructions) -> u8 {
+        let index = Instructions::ALL.iter().position(|&other| other == instruction).unwrap_or(0);
+        self.opcodes[index]
+    }
+
+    // The instruction this map assigns to an opcode byte, or an error for unassigned opcodes.
+    pub fn decode(&self, opcode: u8) -> Result<Instructions, String> {
+        self.opcodes
+            .iter()
+            .position(|&other| other == opcode)
+            .map(|index| Instructions::ALL[index])
+            .ok_or_else(|| format!("Unknown instruction opcode: {}", opcode))
+    }
+}
+
+// Builds a human-readable reference of the 4-byte layout of every instruction,
+// for people writing binaries by hand.
+pub fn encoding_reference() -> String {
+    let mut reference = String::new();
+    for instruction in Instructions::ALL {
+        let format = instruction.operand_format();
+        let syntax = format!("{} {}", instruction.mnemonic(), format.syntax());
+        reference.push_str(syntax.trim_end());
+        reference.push('\n');
+        reference.push_str(&format!("  byte 0 = opcode ({})\n", instruction.opcode()));
+        for (i, description) in format.byte_descriptions().iter().enumerate() {
+            reference.push_str(&format!("  byte {} = {}\n", i + 1, description));
+        }
+    }
+    reference
+}
+
+// Builds an objdump-style listing of an assembled program: one line per instruction with its
+// address, its four bytes in hex and its disassembly, e.g. `0000: 01 00 00 05   MovImm R0 5`.
+// Addresses start at `base`, where the program is loaded (`--base`).
+// Opcodes the map does not assign are shown as `??` instead of stopping the listing.
+pub fn hexdump(program: &[u8], base: u8, opcode_map: &OpcodeMap) -> String {
+    let mut listing = String::new();
+    for (index, bytes) in program.chunks(INSTRUCTION_SIZE as usize).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        listing.push_str(&format!(
+            "{:04}: {:<11}   {}\n",
+            base as usize + index * INSTRUCTION_SIZE as usize, hex.join(" "), disassemble_bytes(bytes, opcode_map)
+        ));
+    }
+    listing
+}
+
+// Formats a program as a Rust constant, e.g. `pub const PROGRAM: [u8; 4] = [...];`, so it can be
+// embedded in Rust code. Each instruction gets its own line with its disassembly as a comment.
+pub fn rust_const(program: &[u8], name: &str, opcode_map: &OpcodeMap) -> String {
+    let mut text = format!("pub const {}: [u8; {}] = [\n", name, program.len());
+    for bytes in program.chunks(INSTRUCTION_SIZE as usize) {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X},", byte)).collect();
+        text.push_str(&format!("    {} // {}\n", hex.join(" "), disassemble_bytes(bytes, opcode_map)));
+    }
+    text.push_str("];\n");
+    text
+}
+
+// Disassembles the bytes of a single instruction, or `??` if they don't decode.
+pub fn disassemble_bytes(bytes: &[u8], opcode_map: &OpcodeMap) -> String {
+    match (opcode_map.decode(bytes[0]), bytes) {
+        (Ok(instruction), &[_, mode_byte, operand1, operand2]) => format_instruction(instruction, mode_byte, operand1, operand2),
+        (Ok(_), _) => String::from("?? (incomplete instruction)"),
+        (Err(_), _) => String::from("??"),
+    }
+}
+
+// Turns a program back into assembly source, one instruction per line, which assembles to the
+// same bytes. Unlike `hexdump`, it fails on bytes that are not valid instructions.
+pub fn disassemble(program: &[u8], opcode_map: &OpcodeMap) -> Result<String, String> {
+    let mut source = String::new();
+    for (index, bytes) in program.chunks(INSTRUCTION_SIZE as usize).enumerate() {
+        let offset = index * INSTRUCTION_SIZE as usize;
+        let &[opcode, mode_byte, operand1, operand2] = bytes else {
+            return Err(format!("Incomplete instruction at offset {}: only {} of {} bytes.", offset, bytes.len(), INSTRUCTION_SIZE));
+        };
+        let instruction = opcode_map.decode(opcode).map_err(|e| format!("{} at offset {}.", e, offset))?;
+        if !instruction.operand_format().accepts_mode_byte(mode_byte) {
+            return Err(format!("Invalid mode byte 0x{:02X} for {} at offset {}.", mode_byte, instruction.mnemonic(), offset));
+        }
+        source.push_str(&format!("{};\n", format_instruction(instruction, mode_byte, operand1, operand2)));
+    }
+    Ok(source)
 }
 
 // Helper function to safely read a value from a register or memory based on operand type.
 // Returns a Result to propagate errors (e.g., invalid register index or memory address).
-fn get_operand_value(cpu: &CPU, operand_type: OperandType, address_or_index: u8, debug_context: &str) -> Result<u8, String> {
+fn get_operand_value(cpu: &Cpu, operand_type: OperandType, address_or_index: u8, debug_context: &str) -> Result<u8, String> {
     match operand_type {
         OperandType::Register => {
             if address_or_index as usize >= cpu.registers.len() {
@@ -86,29 +940,51 @@ fn get_operand_value(cpu: &CPU, operand_type: OperandType, address_or_index: u8,
             Ok(cpu.registers[address_or_index as usize])
         },
         OperandType::Memory => {
-            if address_or_index as usize >= cpu.ram.len() {
+            if address_or_index as usize >= cpu.data_memory().len() {
                 return Err(format!("Runtime error: Invalid memory address {} for {} operand. PC: {}", address_or_index, debug_context, cpu.program_counter));
             }
-            Ok(cpu.ram[address_or_index as usize])
+            Ok(cpu.data_memory()[address_or_index as usize])
+        },
+        OperandType::Indirect => {
+            let address = get_operand_value(cpu, OperandType::Register, address_or_index, debug_context)?;
+            get_operand_value(cpu, OperandType::Memory, address, debug_context)
         },
     }
 }
 
 // Helper function to safely write a value to a register or memory based on operand type.
 // Returns a Result to propagate errors.
-fn set_operand_value(cpu: &mut CPU, operand_type: OperandType, address_or_index: u8, value: u8, debug_context: &str) -> Result<(), String> {
+fn set_operand_value(cpu: &mut Cpu, operand_type: OperandType, address_or_index: u8, value: u8, debug_context: &str) -> Result<(), String> {
     match operand_type {
         OperandType::Register => {
             if address_or_index as usize >= cpu.registers.len() {
                 return Err(format!("Runtime error: Invalid register index {} for {} operand. PC: {}", address_or_index, debug_context, cpu.program_counter));
             }
             cpu.registers[address_or_index as usize] = value;
+            let pc = cpu.program_counter;
+            if let Some(first_writes) = cpu.first_writes.as_mut() {
+                first_writes.registers[address_or_index as usize].get_or_insert(pc);
+            }
         },
         OperandType::Memory => {
-            if address_or_index as usize >= cpu.ram.len() {
+            if address_or_index as usize >= cpu.data_memory().len() {
                 return Err(format!("Runtime error: Invalid memory address {} for {} operand. PC: {}", address_or_index, debug_context, cpu.program_counter));
             }
-            cpu.ram[address_or_index as usize] = value;
+            if cpu.watched.contains(&address_or_index) && !cpu.quiet {
+                let old_value = cpu.data_memory()[address_or_index as usize];
+                let line = format!("Watch: M{} written at PC {}: {} -> {}\n", address_or_index, cpu.program_counter, old_value, value);
+                cpu.pending_diagnostics.push_str(&line);
+            }
+            cpu.data_memory_mut()[address_or_index as usize] = value;
+            cpu.data_high_water = cpu.data_high_water.max(address_or_index as usize + 1);
+            let pc = cpu.program_counter;
+            if let Some(first_writes) = cpu.first_writes.as_mut() {
+                first_writes.ram[address_or_index as usize].get_or_insert(pc);
+            }
+        },
+        OperandType::Indirect => {
+            let address = get_operand_value(cpu, OperandType::Register, address_or_index, debug_context)?;
+            set_operand_value(cpu, OperandType::Memory, address, value, debug_context)?;
         },
     }
     Ok(())
@@ -119,8 +995,9 @@ fn set_operand_value(cpu: &mut CPU, operand_type: OperandType, address_or_index:
 // It takes `OperandType` parameters to determine whether `dest_val_or_addr` and `src_val_or_addr`
 // refer to registers or memory locations.
 fn execute_instruction(
-    cpu: &mut CPU,
+    cpu: &mut Cpu,
     opcode: Instructions,
+    mode_byte: u8,              // Raw mode byte; three-operand instructions (Cas) use it as an extra operand
     dest_type: OperandType,     // Type of the destination operand (Reg/Mem)
     dest_val_or_addr: u8,       // Value (register index or memory address) for destination
     src_type: OperandType,      // Type of the source operand (Reg/Mem)
@@ -133,6 +1010,13 @@ fn execute_instruction(
             // Lower-level operation: Write to destination.
             set_operand_value(cpu, dest_type, dest_val_or_addr, src_value, "Mov destination")?;
         }
+        Instructions::Xchg => {
+            // Read both values before writing either, so exchanging a location with itself leaves it unchanged.
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Xchg destination read")?;
+            let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "Xchg source read")?;
+            set_operand_value(cpu, dest_type, dest_val_or_addr, src_value, "Xchg destination")?;
+            set_operand_value(cpu, src_type, src_val_or_addr, dest_value, "Xchg source")?;
+        }
         Instructions::MovImm => {
             // For MovImm, src_val_or_addr is the immediate value itself.
             // src_type is ignored for MovImm.
@@ -145,12 +1029,53 @@ fn execute_instruction(
             let mut dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Add destination read")?;
             // Perform addition and get carry status.
             let (result, carry) = dest_value.overflowing_add(src_value);
+            let signed_overflow = (dest_value as i8).overflowing_add(src_value as i8).1;
+            cpu.log_overflow(opcode, (dest_value, src_value), result, carry, signed_overflow);
             dest_value = result;
             // Update flags based on the result and carry.
-            cpu.update_flags(dest_value, carry);
+            cpu.update_arithmetic_flags(dest_value, carry, signed_overflow);
             // Lower-level operation: Write result back to destination.
             set_operand_value(cpu, dest_type, dest_val_or_addr, dest_value, "Add destination write")?;
         }
+        Instructions::Adc => {
+            let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "Adc source")?;
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Adc destination read")?;
+            let carry_in = u8::from(cpu.is_flag_set(FLAG_CARRY));
+            // Add in a wider type so a sum pushed past 255 only by the incoming carry still carries out.
+            let sum = dest_value as u16 + src_value as u16 + carry_in as u16;
+            let result = sum as u8;
+            let carry = sum > u8::MAX as u16;
+            let signed_sum = dest_value as i8 as i16 + src_value as i8 as i16 + carry_in as i16;
+            let signed_overflow = i8::try_from(signed_sum).is_err();
+            cpu.log_overflow(opcode, (dest_value, src_value), result, carry, signed_overflow);
+            cpu.update_arithmetic_flags(result, carry, signed_overflow);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "Adc destination write")?;
+        }
+        Instructions::AddImm => {
+            // For AddImm, src_val_or_addr is the immediate value itself, as with MovImm.
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "AddImm destination read")?;
+            let (result, carry) = dest_value.overflowing_add(src_val_or_addr);
+            let signed_overflow = (dest_value as i8).overflowing_add(src_val_or_addr as i8).1;
+            cpu.log_overflow(opcode, (dest_value, src_val_or_addr), result, carry, signed_overflow);
+            cpu.update_arithmetic_flags(result, carry, signed_overflow);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "AddImm destination write")?;
+        }
+        Instructions::SubImm => {
+            // For SubImm, src_val_or_addr is the immediate value itself, as with MovImm.
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "SubImm destination read")?;
+            let (result, borrow) = dest_value.overflowing_sub(src_val_or_addr);
+            let signed_overflow = (dest_value as i8).overflowing_sub(src_val_or_addr as i8).1;
+            cpu.log_overflow(opcode, (dest_value, src_val_or_addr), result, borrow, signed_overflow);
+            cpu.update_arithmetic_flags(result, cpu.borrow_to_carry(borrow), signed_overflow); // Borrow sets carry flag (or clears it, if inverted)
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "SubImm destination write")?;
+        }
+        Instructions::CmpImm => {
+            // Like Cmp, but against the immediate in src_val_or_addr; only the flags are kept.
+            let value = get_operand_value(cpu, dest_type, dest_val_or_addr, "CmpImm operand")?;
+            let (result, borrow) = value.overflowing_sub(src_val_or_addr);
+            let signed_overflow = (value as i8).overflowing_sub(src_val_or_addr as i8).1;
+            cpu.update_arithmetic_flags(result, cpu.borrow_to_carry(borrow), signed_overflow);
+        }
         Instructions::Sub => {
             // Lower-level operation: Read source value.
             let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "Sub source")?;
@@ -158,28 +1083,72 @@ fn execute_instruction(
             let mut dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Sub destination read")?;
             // Perform subtraction and get borrow status (overflowing_sub for unsigned).
             let (result, borrow) = dest_value.overflowing_sub(src_value);
+            let signed_overflow = (dest_value as i8).overflowing_sub(src_value as i8).1;
+            cpu.log_overflow(opcode, (dest_value, src_value), result, borrow, signed_overflow);
             dest_value = result;
             // Update flags based on the result and borrow (carry flag often used for borrow in sub).
-            cpu.update_flags(dest_value, borrow); // Borrow sets carry flag for unsigned subtraction
+            cpu.update_arithmetic_flags(dest_value, cpu.borrow_to_carry(borrow), signed_overflow); // Borrow sets carry flag (or clears it, if inverted)
             // Lower-level operation: Write result back to destination.
             set_operand_value(cpu, dest_type, dest_val_or_addr, dest_value, "Sub destination write")?;
         }
+        Instructions::Sbb => {
+            let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "Sbb source")?;
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Sbb destination read")?;
+            // The incoming borrow is read from Carry with the same `--borrow-carry` convention Sub writes it with.
+            let borrow_in = u8::from(cpu.is_flag_set(FLAG_CARRY) != cpu.inverted_borrow);
+            // Subtract in a wider type so a difference pushed below 0 only by the incoming borrow still borrows.
+            let difference = dest_value as i16 - src_value as i16 - borrow_in as i16;
+            let result = difference as u8;
+            let borrow = difference < 0;
+            let signed_difference = dest_value as i8 as i16 - src_value as i8 as i16 - borrow_in as i16;
+            let signed_overflow = i8::try_from(signed_difference).is_err();
+            cpu.log_overflow(opcode, (dest_value, src_value), result, borrow, signed_overflow);
+            cpu.update_arithmetic_flags(result, cpu.borrow_to_carry(borrow), signed_overflow);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "Sbb destination write")?;
+        }
+        Instructions::Neg => {
+            // Neg is a subtraction from 0, so it borrows (and sets Carry, by default) for any value but 0,
+            // and -128 overflows back to itself.
+            let value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Neg operand read")?;
+            let (result, borrow) = 0u8.overflowing_sub(value);
+            let signed_overflow = 0i8.overflowing_sub(value as i8).1;
+            cpu.log_overflow(opcode, (0, value), result, borrow, signed_overflow);
+            cpu.update_arithmetic_flags(result, cpu.borrow_to_carry(borrow), signed_overflow);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "Neg operand write")?;
+        }
+        Instructions::Ext => {
+            // The host decides what Ext does; without a handler it is an error like an unknown opcode.
+            let pc = cpu.program_counter;
+            let handler = cpu.extension_handler
+                .ok_or_else(|| format!("Runtime error: No extension handler registered for Ext. PC: {}", pc))?;
+            handler(cpu, [mode_byte, dest_val_or_addr, src_val_or_addr])
+                .map_err(|e| format!("Runtime error: Ext failed: {}. PC: {}", e, pc))?;
+        }
         Instructions::Inc => {
             // Inc only uses the destination operand. src_type and src_val_or_addr are ignored.
             let mut val = get_operand_value(cpu, dest_type, dest_val_or_addr, "Inc operand read")?;
             let (result, carry) = val.overflowing_add(1);
+            let signed_overflow = (val as i8).overflowing_add(1).1;
+            cpu.log_overflow(opcode, (val, 1), result, carry, signed_overflow);
             val = result;
-            cpu.update_flags(val, carry);
+            cpu.update_arithmetic_flags(val, carry, signed_overflow);
             set_operand_value(cpu, dest_type, dest_val_or_addr, val, "Inc operand write")?;
         }
         Instructions::Dec => {
             // Dec only uses the destination operand. src_type and src_val_or_addr are ignored.
             let mut val = get_operand_value(cpu, dest_type, dest_val_or_addr, "Dec operand read")?;
             let (result, borrow) = val.overflowing_sub(1);
+            let signed_overflow = (val as i8).overflowing_sub(1).1;
+            cpu.log_overflow(opcode, (val, 1), result, borrow, signed_overflow);
             val = result;
-            cpu.update_flags(val, borrow); // Borrow sets carry flag for unsigned subtraction
+            cpu.update_arithmetic_flags(val, cpu.borrow_to_carry(borrow), signed_overflow); // Borrow sets carry flag (or clears it, if inverted)
             set_operand_value(cpu, dest_type, dest_val_or_addr, val, "Dec operand write")?;
         }
+        Instructions::Clr => {
+            // Clr only uses the destination operand. Zeroing always sets the Zero Flag and clears Carry.
+            set_operand_value(cpu, dest_type, dest_val_or_addr, 0, "Clr operand write")?;
+            cpu.update_flags(0, false);
+        }
         Instructions::Cmp => {
             // Compare: Calculates dest - src and sets flags without storing the result.
             // dest_val_or_addr is operand1, src_val_or_addr is operand2
@@ -188,36 +1157,287 @@ fn execute_instruction(
 
             // Perform subtraction to set flags. We only care about the flags, not the result.
             let (result, borrow) = op1_value.overflowing_sub(op2_value);
-            cpu.update_flags(result, borrow);
+            let signed_overflow = (op1_value as i8).overflowing_sub(op2_value as i8).1;
+            cpu.update_arithmetic_flags(result, cpu.borrow_to_carry(borrow), signed_overflow);
         }
         Instructions::JmpAddr => {
             // JmpAddr uses dest_val_or_addr as the target address.
-            cpu.program_counter = dest_val_or_addr;
+            cpu.jump_to(dest_val_or_addr)?;
         }
         Instructions::JmpEq => {
             // Jump if Equal (ZF is set)
             if cpu.is_flag_set(FLAG_ZERO) {
-                cpu.program_counter = dest_val_or_addr;
+                cpu.jump_to(dest_val_or_addr)?;
             } else {
-                cpu.program_counter += INSTRUCTION_SIZE; // No jump, move to next instruction
+                cpu.program_counter = cpu.next_instruction_address()?; // No jump, move to next instruction
             }
         }
         Instructions::JmpNe => {
             // Jump if Not Equal (ZF is clear)
             if !cpu.is_flag_set(FLAG_ZERO) {
-                cpu.program_counter = dest_val_or_addr;
+                cpu.jump_to(dest_val_or_addr)?;
             } else {
-                cpu.program_counter += INSTRUCTION_SIZE; // No jump, move to next instruction
+                cpu.program_counter = cpu.next_instruction_address()?; // No jump, move to next instruction
             }
         }
         Instructions::JmpGt => {
             // Jump if Greater Than (ZF is clear AND Carry Flag is clear) for unsigned comparison
             // If A > B, then A - B does not borrow and result is not zero.
-            if !cpu.is_flag_set(FLAG_ZERO) && !cpu.is_flag_set(FLAG_CARRY) {
-                cpu.program_counter = dest_val_or_addr;
+            // With `--borrow-carry inverted`, "no borrow" is Carry set instead.
+            if cpu.is_unsigned_greater() {
+                cpu.jump_to(dest_val_or_addr)?;
+            } else {
+                cpu.program_counter = cpu.next_instruction_address()?; // No jump, move to next instruction
+            }
+        }
+        Instructions::JmpRel => {
+            // The offset counts from this jump's own address, so `JmpRel 0` jumps to itself.
+            let target = cpu.program_counter as i16 + dest_val_or_addr as i8 as i16;
+            let target = u8::try_from(target).map_err(|_| {
+                format!("Runtime error: Jump target {} is outside the {}-byte program. PC: {}", target, cpu.program_size, cpu.program_counter)
+            })?;
+            cpu.jump_to(target)?;
+        }
+        Instructions::Loop => {
+            // Only the Zero flag is updated, so the other flags survive the loop for the body to use.
+            let counter = get_operand_value(cpu, OperandType::Register, LOOP_COUNTER, "Loop counter")?.wrapping_sub(1);
+            set_operand_value(cpu, OperandType::Register, LOOP_COUNTER, counter, "Loop counter")?;
+            cpu.assign_flag(FLAG_ZERO, counter == 0);
+            if counter != 0 {
+                cpu.jump_to(dest_val_or_addr)?;
+            } else {
+                cpu.program_counter = cpu.next_instruction_address()?; // Counter ran out, move to next instruction
+            }
+        }
+        Instructions::JmpC | Instructions::JmpNc => {
+            // Test the raw Carry flag, so the meaning after Sub/Cmp depends on `--borrow-carry`.
+            if cpu.is_flag_set(FLAG_CARRY) == (opcode == Instructions::JmpC) {
+                cpu.jump_to(dest_val_or_addr)?;
+            } else {
+                cpu.program_counter = cpu.next_instruction_address()?; // No jump, move to next instruction
+            }
+        }
+        Instructions::Cas => {
+            // Cas is encoded as [opcode, address, expected register, new register]:
+            // the mode byte holds the RAM address since all three operands have fixed types.
+            // Reading it as a memory operand checks it against the size of RAM.
+            let current = get_operand_value(cpu, OperandType::Memory, mode_byte, "Cas address")?;
+            let expected = get_operand_value(cpu, OperandType::Register, dest_val_or_addr, "Cas expected")?;
+            let new_value = get_operand_value(cpu, OperandType::Register, src_val_or_addr, "Cas new value")?;
+
+            // Swap only if memory still holds the expected value; the Zero Flag reports success.
+            if current == expected {
+                set_operand_value(cpu, OperandType::Memory, mode_byte, new_value, "Cas address")?;
+                cpu.set_flag(FLAG_ZERO);
+            } else {
+                cpu.clear_flag(FLAG_ZERO);
+            }
+        }
+        Instructions::IMul => {
+            // Operands are reinterpreted as signed bytes for the multiplication.
+            let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "IMul source")? as i8;
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "IMul destination read")? as i8;
+            // The product is truncated to 8 bits; Overflow is set if it didn't fit in an i8.
+            let (result, overflow) = dest_value.overflowing_mul(src_value);
+            cpu.log_overflow(opcode, (dest_value as u8, src_value as u8), result as u8, false, overflow);
+            cpu.update_signed_flags(result as u8, overflow);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result as u8, "IMul destination write")?;
+        }
+        Instructions::IDiv => {
+            let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "IDiv source")? as i8;
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "IDiv destination read")? as i8;
+            if src_value == 0 {
+                return Err(format!("Runtime error: division by zero. PC: {}", cpu.program_counter));
+            }
+            // -128 / -1 = 128 does not fit in an i8: the result wraps back to -128 and Overflow is set.
+            let (result, overflow) = dest_value.overflowing_div(src_value);
+            cpu.log_overflow(opcode, (dest_value as u8, src_value as u8), result as u8, false, overflow);
+            cpu.update_signed_flags(result as u8, overflow);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result as u8, "IDiv destination write")?;
+        }
+        Instructions::Mul => {
+            let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "Mul source")?;
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Mul destination read")?;
+            // The product is truncated to 8 bits; Carry is set if the full product exceeded 255.
+            let (result, carry) = dest_value.overflowing_mul(src_value);
+            cpu.log_overflow(opcode, (dest_value, src_value), result, carry, false);
+            cpu.update_flags(result, carry);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "Mul destination write")?;
+        }
+        Instructions::Div => {
+            let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "Div source")?;
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Div destination read")?;
+            // `checked_div` only fails on a zero divisor, which is reported instead of panicking.
+            let result = dest_value.checked_div(src_value)
+                .ok_or_else(|| format!("Runtime error: division by zero. PC: {}", cpu.program_counter))?;
+            // Unsigned division can't overflow, so Carry is always cleared.
+            cpu.update_flags(result, false);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "Div destination write")?;
+        }
+        Instructions::Mod => {
+            let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "Mod source")?;
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Mod destination read")?;
+            // Like Div, a zero divisor is reported instead of panicking.
+            let result = dest_value.checked_rem(src_value)
+                .ok_or_else(|| format!("Runtime error: division by zero. PC: {}", cpu.program_counter))?;
+            cpu.update_flags(result, false);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "Mod destination write")?;
+        }
+        Instructions::And | Instructions::Or | Instructions::Xor => {
+            let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "bitwise source")?;
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "bitwise destination read")?;
+            let result = match opcode {
+                Instructions::And => dest_value & src_value,
+                Instructions::Or => dest_value | src_value,
+                _ => dest_value ^ src_value,
+            };
+            // Bitwise operations never carry, so Carry is always cleared.
+            cpu.update_flags(result, false);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "bitwise destination write")?;
+        }
+        Instructions::Test => {
+            // Same flags as And (Zero and Sign from the result, Carry cleared), but both operands are left unchanged.
+            let src_value = get_operand_value(cpu, src_type, src_val_or_addr, "Test source")?;
+            let dest_value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Test destination read")?;
+            cpu.update_flags(dest_value & src_value, false);
+        }
+        Instructions::Shl | Instructions::Shr => {
+            // Only the low three bits of the count are used, so shifting by 8 is the same as by 0.
+            let count = get_operand_value(cpu, src_type, src_val_or_addr, "shift count")? & 0b111;
+            let value = get_operand_value(cpu, dest_type, dest_val_or_addr, "shift destination read")?;
+            // Carry receives the last bit shifted out; a shift by 0 moves nothing out and clears it.
+            let (result, carry) = match (opcode, count) {
+                (_, 0) => (value, false),
+                (Instructions::Shl, _) => (value << count, (value >> (8 - count)) & 1 != 0),
+                _ => (value >> count, (value >> (count - 1)) & 1 != 0),
+            };
+            cpu.update_flags(result, carry);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "shift destination write")?;
+        }
+        Instructions::Push => {
+            // The slot at the limit isn't part of the stack (at SP 0, decrementing would wrap around).
+            if cpu.stack_pointer == cpu.stack_limit {
+                return Err(format!("Runtime error: stack overflow. PC: {}", cpu.program_counter));
+            }
+            let value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Push source")?;
+            set_operand_value(cpu, OperandType::Memory, cpu.stack_pointer, value, "Push stack slot")?;
+            cpu.stack_pointer -= 1;
+        }
+        Instructions::Pop => {
+            // SP at its base means nothing has been pushed.
+            if cpu.stack_pointer == cpu.stack_base {
+                return Err(format!("Runtime error: stack underflow. PC: {}", cpu.program_counter));
+            }
+            cpu.stack_pointer += 1;
+            let value = get_operand_value(cpu, OperandType::Memory, cpu.stack_pointer, "Pop stack slot")?;
+            set_operand_value(cpu, dest_type, dest_val_or_addr, value, "Pop destination")?;
+        }
+        Instructions::JmpLt | Instructions::JmpGe | Instructions::JmpLe => {
+            // Signed counterparts of JmpGt, reading the Sign and Overflow flags left by Cmp.
+            let condition = match opcode {
+                Instructions::JmpLt => cpu.is_signed_less(),
+                Instructions::JmpGe => !cpu.is_signed_less(),
+                _ => cpu.is_signed_less() || cpu.is_flag_set(FLAG_ZERO),
+            };
+            if condition {
+                cpu.jump_to(dest_val_or_addr)?;
+            } else {
+                cpu.program_counter = cpu.next_instruction_address()?; // No jump, move to next instruction
+            }
+        }
+        Instructions::Call => {
+            // Save the address of the instruction after the call in the next stack slot, like Push.
+            if cpu.stack_pointer == cpu.stack_limit {
+                return Err(format!("Runtime error: stack overflow. PC: {}", cpu.program_counter));
+            }
+            let return_address = cpu.next_instruction_address()?;
+            set_operand_value(cpu, OperandType::Memory, cpu.stack_pointer, return_address, "Call stack slot")?;
+            cpu.stack_pointer -= 1;
+            cpu.jump_to(dest_val_or_addr)?;
+        }
+        Instructions::Ret => {
+            // Jump back to the return address saved by the matching Call, like Pop.
+            if cpu.stack_pointer == cpu.stack_base {
+                return Err(format!("Runtime error: stack underflow. PC: {}", cpu.program_counter));
+            }
+            cpu.stack_pointer += 1;
+            let return_address = get_operand_value(cpu, OperandType::Memory, cpu.stack_pointer, "Ret stack slot")?;
+            cpu.jump_to(return_address)?;
+        }
+        Instructions::Not => {
+            let value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Not operand read")?;
+            let result = !value;
+            cpu.update_flags(result, false); // No carry out of a bitwise operation.
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result, "Not operand write")?;
+        }
+        Instructions::Abs => {
+            let value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Abs operand read")? as i8;
+            // |-128| = 128 does not fit in an i8: the value is left as -128 and Overflow is set.
+            let (result, overflow) = value.overflowing_abs();
+            cpu.log_overflow(opcode, (value as u8, 0), result as u8, false, overflow);
+            cpu.update_signed_flags(result as u8, overflow);
+            set_operand_value(cpu, dest_type, dest_val_or_addr, result as u8, "Abs operand write")?;
+        }
+        Instructions::Dbg => {
+            // Dump the state without touching it, so the program continues exactly as before.
+            if !cpu.quiet {
+                let state = format_state(cpu, &format!("CPU STATE AT PC {}", cpu.program_counter));
+                cpu.pending_diagnostics.push_str(&state);
+            }
+        }
+        Instructions::Out => {
+            // The text is written out by `step` once the instruction has completed.
+            let value = get_operand_value(cpu, dest_type, dest_val_or_addr, "Out source")?;
+            if mode_byte & OUT_CHARACTER != 0 {
+                cpu.pending_output.push(value);
             } else {
-                cpu.program_counter += INSTRUCTION_SIZE; // No jump, move to next instruction
+                cpu.pending_output.extend_from_slice(format!("{}\n", value).as_bytes());
+            }
+        }
+        Instructions::SetEq | Instructions::SetNe | Instructions::SetGt => {
+            // Branchless counterparts of JmpEq/JmpNe/JmpGt: write the condition as 1 or 0, flags unchanged.
+            let condition = match opcode {
+                Instructions::SetEq => cpu.is_flag_set(FLAG_ZERO),
+                Instructions::SetNe => !cpu.is_flag_set(FLAG_ZERO),
+                _ => cpu.is_unsigned_greater(),
+            };
+            set_operand_value(cpu, dest_type, dest_val_or_addr, u8::from(condition), "SetCC destination")?;
+        }
+        Instructions::Rol16 | Instructions::Ror16 => {
+            // The pair R<n>:R<n+1> holds a 16-bit value with the high byte in the even register.
+            let high = dest_val_or_addr as usize;
+            if !high.is_multiple_of(2) || high + 1 >= cpu.registers.len() {
+                return Err(format!("Runtime error: Invalid register pair R{} for {}. PC: {}", high, opcode.mnemonic(), cpu.program_counter));
             }
+            let value = u16::from_be_bytes([cpu.registers[high], cpu.registers[high + 1]]);
+            // Carry receives the bit rotated out, which is also the bit rotated back in at the other end.
+            let (result, carry) = if opcode == Instructions::Rol16 {
+                (value.rotate_left(1), value & 0x8000 != 0)
+            } else {
+                (value.rotate_right(1), value & 0x0001 != 0)
+            };
+            let [high_byte, low_byte] = result.to_be_bytes();
+            set_operand_value(cpu, OperandType::Register, high as u8, high_byte, "pair high byte")?;
+            set_operand_value(cpu, OperandType::Register, high as u8 + 1, low_byte, "pair low byte")?;
+            cpu.assign_flag(FLAG_ZERO, result == 0);
+            cpu.assign_flag(FLAG_CARRY, carry);
+        }
+        Instructions::InP => {
+            // For InP, src_val_or_addr is the port number; src_type is ignored.
+            let value = cpu.ports[src_val_or_addr as usize].input.pop_front().ok_or_else(|| {
+                format!("Runtime error: No input available on port {}. PC: {}", src_val_or_addr, cpu.program_counter)
+            })?;
+            set_operand_value(cpu, dest_type, dest_val_or_addr, value, "InP destination")?;
+        }
+        Instructions::In => {
+            // The value was already read from the input stream by `step`.
+            let value = cpu.input_value.take().ok_or_else(|| format!("Runtime error: No input value read for In. PC: {}", cpu.program_counter))?;
+            set_operand_value(cpu, dest_type, dest_val_or_addr, value, "In destination")?;
+        }
+        Instructions::OutP => {
+            // For OutP, dest_val_or_addr is the port number; dest_type is ignored.
+            let value = get_operand_value(cpu, src_type, src_val_or_addr, "OutP source")?;
+            cpu.ports[dest_val_or_addr as usize].output.push(value);
         }
         Instructions::HLT => {
             // HLT is handled directly in run_program to break the loop.
@@ -227,132 +1447,2260 @@ fn execute_instruction(
     Ok(())
 }
 
-// Loads the program bytes into the CPU's program memory.
-fn load_program(cpu: &mut CPU, program: &[u8]) {
-    for (i, &instruction_byte) in program.iter().enumerate() {
-        if i < cpu.memory.len() { // Ensure we don't write beyond memory bounds
-            cpu.memory[i] = instruction_byte;
-        } else {
-            eprintln!("Warning: Program exceeds memory size. Instruction at index {} ignored.", i);
-            break;
+// The address a `JmpRel` at `pc` with the given offset byte jumps to, if it is not before the program start.
+fn relative_target(pc: usize, offset: u8) -> Option<usize> {
+    pc.checked_add_signed(offset as i8 as isize)
+}
+
+// Splits a program into basic blocks: straight-line runs of instructions that are only
+// entered at the top and only left at the bottom. `program` holds memory from address 0 and the
+// code starts at `entry`. Returns (start, end) byte offsets, end exclusive.
+fn basic_blocks(program: &[u8], entry: usize, opcode_map: &OpcodeMap) -> Vec<(usize, usize)> {
+    let program_size = program.len() - program.len() % INSTRUCTION_SIZE as usize;
+
+    // A block starts at the program entry, at every jump target, and after every jump or HLT.
+    let mut leaders = vec![entry];
+    for pc in (entry..program_size).step_by(INSTRUCTION_SIZE as usize) {
+        match opcode_map.decode(program[pc]) {
+            Ok(instruction) if instruction.operand_format() == OperandFormat::Address => {
+                leaders.push(program[pc + 2] as usize);
+                leaders.push(pc + INSTRUCTION_SIZE as usize);
+            }
+            Ok(Instructions::JmpRel) => {
+                leaders.extend(relative_target(pc, program[pc + 2]));
+                leaders.push(pc + INSTRUCTION_SIZE as usize);
+            }
+            Ok(Instructions::HLT | Instructions::Ret) => leaders.push(pc + INSTRUCTION_SIZE as usize),
+            _ => {}
         }
     }
+    // Only instruction-aligned targets inside the program can start a block.
+    leaders.retain(|&leader| leader >= entry && leader < program_size && leader % INSTRUCTION_SIZE as usize == 0);
+    leaders.sort_unstable();
+    leaders.dedup();
+
+    let mut blocks = Vec::new();
+    for (i, &start) in leaders.iter().enumerate() {
+        let end = leaders.get(i + 1).copied().unwrap_or(program_size);
+        blocks.push((start, end));
+    }
+    blocks
 }
 
-// Runs the loaded program in the CPU.
-// It fetches, decodes, and executes instructions sequentially.
-// Returns a Result to indicate if any runtime errors occurred (e.g., unknown opcode, invalid address).
-fn run_program(cpu: &mut CPU, program_size: usize) -> Result<(), String> {
-    while (cpu.program_counter as usize) < program_size {
-        // Check if there are enough bytes for a full 4-byte instruction
-        if (cpu.program_counter as usize) + (INSTRUCTION_SIZE as usize) > program_size {
-            return Err(format!("Program ended unexpectedly at PC {}. Incomplete instruction.", cpu.program_counter));
+// Returns the start addresses of the blocks control can flow to after the block ending at `end`.
+fn block_successors(program: &[u8], opcode_map: &OpcodeMap, end: usize) -> Vec<usize> {
+    let last = end - INSTRUCTION_SIZE as usize;
+    let mut successors = match opcode_map.decode(program[last]) {
+        Ok(Instructions::JmpAddr) => vec![program[last + 2] as usize],
+        Ok(Instructions::JmpRel) => relative_target(last, program[last + 2]).into_iter().collect(),
+        Ok(instruction) if instruction.operand_format() == OperandFormat::Address => vec![program[last + 2] as usize, end],
+        // Where Ret goes depends on the caller, which isn't known statically.
+        Ok(Instructions::HLT | Instructions::Ret) | Err(_) => vec![],
+        Ok(_) => vec![end],
+    };
+    successors.retain(|&successor| successor + (INSTRUCTION_SIZE as usize) <= program.len());
+    successors
+}
+
+// Builds a Graphviz graph of the program's basic blocks, labelled with their instructions
+// and how many times each block was entered. Blocks executed at least half as often as the
+// hottest block are filled red. The program is laid out as for `basic_blocks`.
+fn hot_cfg_graph(program: &[u8], entry: usize, opcode_map: &OpcodeMap, pc_counts: &[u64]) -> String {
+    let blocks = basic_blocks(program, entry, opcode_map);
+    // A block's count is the count of its first instruction, since blocks are only entered at the top.
+    let max_count = blocks.iter().map(|&(start, _)| pc_counts[start]).max().unwrap_or(0);
+
+    let mut graph = String::from("digraph cfg {\n    node [shape=box];\n");
+    for &(start, end) in &blocks {
+        let count = pc_counts[start];
+        let mnemonics: Vec<&str> = (start..end)
+            .step_by(INSTRUCTION_SIZE as usize)
+            .map(|pc| opcode_map.decode(program[pc]).map(|i| i.mnemonic()).unwrap_or("?"))
+            .collect();
+        let style = if count > 0 && count * 2 >= max_count { ", style=filled, fillcolor=\"#ff9999\"" } else { "" };
+        graph.push_str(&format!(
+            "    b{} [label=\"PC {}-{}\\n{}\\nexecuted {}x\"{}];\n",
+            start, start, end - 1, mnemonics.join("\\n"), count, style
+        ));
+    }
+    for &(start, end) in &blocks {
+        for successor in block_successors(program, opcode_map, end) {
+            graph.push_str(&format!("    b{} -> b{};\n", start, successor));
         }
+    }
+    graph.push_str("}\n");
+    graph
+}
 
-        // Fetch the 4 bytes of the current instruction
-        let opcode_val = cpu.memory[cpu.program_counter as usize];
-        let mode_byte = cpu.memory[(cpu.program_counter + 1) as usize];
-        let operand1_val = cpu.memory[(cpu.program_counter + 2) as usize];
-        let operand2_val = cpu.memory[(cpu.program_counter + 3) as usize];
+// Renders a decoded instruction back into assembly syntax, e.g. `Add R0 M5` or `JmpEq 16`.
+fn format_instruction(instruction: Instructions, mode_byte: u8, operand1_val: u8, operand2_val: u8) -> String {
+    // Renders a Reg/Mem operand in the syntax selected by its mode bits.
+    let reg_mem = |value: u8, operand_type: OperandType| match operand_type {
+        OperandType::Register => format!("R{}", value),
+        OperandType::Memory => format!("M{}", value),
+        OperandType::Indirect => format!("[R{}]", value),
+    };
+    let dest_type = OperandType::from_mode_byte(mode_byte, 0b01, DEST_INDIRECT);
+    let src_type = OperandType::from_mode_byte(mode_byte, 0b10, SRC_INDIRECT);
 
-        // Convert the opcode byte to an `Instructions` enum variant.
-        // `try_from` will return an error if the opcode is unknown.
-        let opcode = Instructions::try_from(opcode_val)?;
+    let operands = match instruction.operand_format() {
+        OperandFormat::DestSrc => format!("{} {}", reg_mem(operand1_val, dest_type), reg_mem(operand2_val, src_type)),
+        OperandFormat::DestImm | OperandFormat::DestPort => format!("{} {}", reg_mem(operand1_val, dest_type), operand2_val),
+        OperandFormat::Single => reg_mem(operand1_val, dest_type),
+        OperandFormat::Address => operand1_val.to_string(),
+        OperandFormat::Offset => (operand1_val as i8).to_string(),
+        OperandFormat::CompareSwap => format!("M{} R{} R{}", mode_byte, operand1_val, operand2_val),
+        OperandFormat::PortSrc => format!("{} {}", operand1_val, reg_mem(operand2_val, src_type)),
+        OperandFormat::RegisterPair => format!("R{}", operand1_val),
+        OperandFormat::Output if mode_byte & OUT_CHARACTER != 0 => format!("{} char", reg_mem(operand1_val, dest_type)),
+        OperandFormat::Output => reg_mem(operand1_val, dest_type),
+        OperandFormat::RawBytes => format!("{} {} {}", mode_byte, operand1_val, operand2_val),
+        OperandFormat::NoOperands => return instruction.mnemonic().to_string(),
+    };
+    format!("{} {}", instruction.mnemonic(), operands)
+}
 
-        // If the instruction is HLT, print message and terminate execution.
-        if opcode == Instructions::HLT {
-            println!("Halted.");
-            return Ok(());
+// Cycles an instruction with the given mode byte takes: its base cost plus `MEMORY_OPERAND_CYCLES`
+// for each operand the mode byte puts in memory, directly (`M#`) or through a register (`[R#]`).
+fn instruction_cycles(instruction: Instructions, mode_byte: u8) -> u64 {
+    let memory_operands = match instruction.operand_format() {
+        OperandFormat::CompareSwap => 1, // Always compares and swaps a memory cell.
+        OperandFormat::RawBytes => 0, // The mode byte is the extension's own data.
+        _ => (mode_byte & (0b11 | DEST_INDIRECT | SRC_INDIRECT)).count_ones() as u64,
+    };
+    instruction.cycles() + memory_operands * MEMORY_OPERAND_CYCLES
+}
+
+// Lists the instructions that executed with their execution counts and cycles, most frequent
+// first (ties in opcode order), for `--profile`. `counts` and `cycles` are indexed by default opcode.
+fn instruction_profile(counts: &[u64], cycles: &[u64]) -> String {
+    let mut executed: Vec<(Instructions, u64)> = Instructions::ALL
+        .iter()
+        .map(|&instruction| (instruction, counts[instruction.opcode() as usize]))
+        .filter(|&(_, count)| count > 0)
+        .collect();
+    executed.sort_by_key(|&(_, count)| std::cmp::Reverse(count)); // Stable, so equal counts keep opcode order.
+    let mut profile = String::from("Instruction profile:\n");
+    for (instruction, count) in executed {
+        profile.push_str(&format!("  {}: {} ({} cycles)\n", instruction.mnemonic(), count, cycles[instruction.opcode() as usize]));
+    }
+    profile
+}
+
+// Formats the instruction counts per call stack in the folded stack format of flamegraph tools,
+// one `main;outer;inner 12` line per stack. Subroutines are named after the first label at their
+// entry address, or `sub_<ADDR>` without one.
+fn folded_stacks(profile: &CallStackProfile, labels: &[(String, u8)]) -> String {
+    let name = |address: u8| {
+        labels.iter().find(|&&(_, label_address)| label_address == address).map_or_else(|| format!("sub_{}", address), |(name, _)| name.clone())
+    };
+    let mut folded = String::new();
+    for (stack, count) in &profile.counts {
+        let frames: Vec<String> = std::iter::once(String::from("main")).chain(stack.iter().map(|&address| name(address))).collect();
+        folded.push_str(&format!("{} {}\n", frames.join(";"), count));
+    }
+    folded
+}
+
+// Formats the execution counts of the instructions that ran as CSV rows, in opcode order, for
+// `--stats-csv`: `mnemonic,count,cycles`, with the cycles from `instruction_cycles`.
+fn instruction_stats_csv(counts: &[u64], cycles: &[u64]) -> String {
+    let mut csv = String::from("mnemonic,count,cycles\n");
+    for instruction in Instructions::ALL {
+        let count = counts[instruction.opcode() as usize];
+        if count > 0 {
+            csv.push_str(&format!("{},{},{}\n", instruction.mnemonic(), count, cycles[instruction.opcode() as usize]));
         }
+    }
+    csv
+}
 
-        // Decode operand types from the `mode_byte`:
-        // Bit 0 (0b01) controls dest_type: 1 means Memory, 0 means Register.
-        // Bit 1 (0b10) controls src_type: 1 means Memory, 0 means Register.
-        let dest_type = if (mode_byte & 0b01) != 0 { OperandType::Memory } else { OperandType::Register };
-        let src_type = if (mode_byte & 0b10) != 0 { OperandType::Memory } else { OperandType::Register };
-
-        // Execute the decoded instruction with its operands and types.
-        // Errors from `execute_instruction` (e.g., invalid register/memory access) are propagated.
-        execute_instruction(
-            cpu,
-            opcode,
-            dest_type,
-            operand1_val,
-            src_type,
-            operand2_val,
-        )?;
-
-        // For jump instructions, PC is handled within execute_instruction.
-        // For all other instructions, we advance PC by INSTRUCTION_SIZE.
-        match opcode {
-            Instructions::JmpAddr | Instructions::JmpEq | Instructions::JmpNe | Instructions::JmpGt => {
-                // PC was already set/incremented inside execute_instruction. Do nothing here.
-            },
-            _ => {
-                // For all non-jump instructions, advance PC to the next instruction.
-                cpu.program_counter += INSTRUCTION_SIZE;
+// Compares the program's code size and data footprint against the `--budget` limits,
+// with one line per checked limit followed by an overall pass/fail verdict.
+fn budget_report(code_size: usize, data_footprint: usize, code_budget: Option<usize>, data_budget: Option<usize>) -> String {
+    let mut report = String::new();
+    let mut passed = true;
+    for (name, used, budget) in [("Code size", code_size, code_budget), ("Data footprint", data_footprint, data_budget)] {
+        if let Some(budget) = budget {
+            let verdict = if used <= budget { "ok" } else { "EXCEEDED" };
+            passed &= used <= budget;
+            report.push_str(&format!("{}: {} bytes (budget {}) - {}\n", name, used, budget, verdict));
+        }
+    }
+    report.push_str(if passed { "Budget check passed.\n" } else { "Budget check failed.\n" });
+    report
+}
+
+// Formats an inclusive address range of a memory as a hex grid, 16 bytes per row,
+// each row prefixed with the address of its first byte.
+fn hex_grid(memory: &[u8], start: u8, end: u8) -> String {
+    let mut grid = String::new();
+    for (index, bytes) in memory[start as usize..=end as usize].chunks(16).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        grid.push_str(&format!("{:04}: {}\n", start as usize + index * 16, hex.join(" ")));
+    }
+    grid
+}
+
+// Describes the state an executed instruction affected, for `--trace`: the new value of its
+// Reg/Mem destination (e.g. `R0 = 7`), or the PC for instructions without one, such as jumps.
+// Comparisons show the flags they set (e.g. `ZF=1 CF=0 SF=0 OF=0`), `Push` the new stack pointer
+// and `Out` the PC, since their Reg/Mem operand is only read.
+// An `[R#]` destination is shown as the cell at `dest_address`, the address the register held
+// before the instruction ran, since the instruction may have changed the register since.
+// The operands were already validated by executing the instruction, so indexing can't fail.
+fn format_trace_effect(cpu: &Cpu, instruction: Instructions, mode_byte: u8, operand1_val: u8, dest_address: Option<u8>) -> String {
+    match instruction {
+        Instructions::Cmp | Instructions::CmpImm | Instructions::Test => {
+            let flags: Vec<String> = [(FLAG_ZERO, "ZF"), (FLAG_CARRY, "CF"), (FLAG_SIGN, "SF"), (FLAG_OVERFLOW, "OF")]
+                .iter()
+                .map(|&(flag, name)| format!("{}={}", name, u8::from(cpu.is_flag_set(flag))))
+                .collect();
+            return flags.join(" ");
+        }
+        Instructions::Push => return format!("SP = {}", cpu.stack_pointer),
+        Instructions::Out => return format!("PC = {}", cpu.program_counter),
+        _ => {}
+    }
+    match instruction.operand_format() {
+        OperandFormat::DestSrc | OperandFormat::DestImm | OperandFormat::Single | OperandFormat::DestPort | OperandFormat::Output => {
+            match OperandType::from_mode_byte(mode_byte, 0b01, DEST_INDIRECT) {
+                OperandType::Memory => format!("M{} = {}", operand1_val, cpu.data_memory()[operand1_val as usize]),
+                OperandType::Register => format!("R{} = {}", operand1_val, cpu.registers[operand1_val as usize]),
+                OperandType::Indirect => {
+                    let address = dest_address.unwrap_or(0);
+                    format!("M{} = {}", address, cpu.data_memory()[address as usize])
+                }
             }
         }
+        OperandFormat::CompareSwap => format!("M{} = {}", mode_byte, cpu.data_memory()[mode_byte as usize]),
+        OperandFormat::RegisterPair => {
+            let high = operand1_val as usize;
+            format!("R{}:R{} = {}", high, high + 1, u16::from_be_bytes([cpu.registers[high], cpu.registers[high + 1]]))
+        }
+        OperandFormat::PortSrc | OperandFormat::Address | OperandFormat::Offset | OperandFormat::RawBytes | OperandFormat::NoOperands => format!("PC = {}", cpu.program_counter),
     }
-    Ok(())
 }
 
-// Implements the `TryFrom` trait to safely convert a `u8` (opcode byte) into an `Instructions` enum.
-// This allows error handling for invalid opcode values.
-impl TryFrom<u8> for Instructions {
-    type Error = String; // The error type for conversion failures.
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(Instructions::Mov),
-            1 => Ok(Instructions::MovImm),
-            2 => Ok(Instructions::Add),
-            3 => Ok(Instructions::Sub),
-            4 => Ok(Instructions::Inc),
-            5 => Ok(Instructions::Dec),
-            6 => Ok(Instructions::Cmp),      // New opcode for Cmp
-            7 => Ok(Instructions::JmpAddr),  // Opcode for JmpAddr (shifted)
-            8 => Ok(Instructions::JmpEq),    // New opcode for JmpEq
-            9 => Ok(Instructions::JmpNe),    // New opcode for JmpNe
-            10 => Ok(Instructions::JmpGt),   // New opcode for JmpGt
-            11 => Ok(Instructions::HLT),     // HLT opcode (shifted)
-            _ => Err(format!("Unknown instruction opcode: {}", value)), // Return an error for unrecognized opcodes.
-        }
-    }
-}
-
-// Public function to start the emulation process.
-pub fn run_emulation(program_vector: Vec<u8>, print_usage: bool) {
-    // Initialize CPU with all registers and memory set to 0.
-    let mut cpu = CPU {
-        registers: [0, 0, 0, 0],
-        memory: [0; MEMORY_SIZE], // Program memory
-        ram: [0; MEMORY_SIZE],    // Data memory
-        program_counter: 0,
-        flags: 0, // Initialize flags to 0
+// The RAM addresses an instruction's `[R#]` operands refer to, as (destination, source), read from the
+// registers before the instruction runs. Cas uses its mode byte as an address, so it has none.
+fn indirect_addresses(cpu: &Cpu, instruction: Instructions, mode_byte: u8, operand1_val: u8, operand2_val: u8) -> (Option<u8>, Option<u8>) {
+    let (has_dest, has_src) = match instruction.operand_format() {
+        OperandFormat::DestSrc => (true, true),
+        OperandFormat::DestImm | OperandFormat::Single | OperandFormat::DestPort | OperandFormat::Output => (true, false),
+        OperandFormat::PortSrc => (false, true),
+        _ => (false, false),
     };
+    let address = |present: bool, memory_bit: u8, indirect_bit: u8, register: u8| {
+        if present && OperandType::from_mode_byte(mode_byte, memory_bit, indirect_bit) == OperandType::Indirect {
+            cpu.registers.get(register as usize).copied()
+        } else {
+            None
+        }
+    };
+    (address(has_dest, 0b01, DEST_INDIRECT, operand1_val), address(has_src, 0b10, SRC_INDIRECT, operand2_val))
+}
 
-    // Load the provided program into the CPU's memory.
-    let program = &program_vector[..];
-    load_program(&mut cpu, &program);
-
-    // Run the program and handle any emulation errors.
-    if let Err(e) = run_program(&mut cpu, program.len()) {
-        eprintln!("Emulation error: {}", e);
+// Names the cells an instruction's `[R#]` operands resolved to, e.g. `  ; [R1] = M37`, or nothing
+// when it has none, so `--trace` shows where pointer accesses actually went.
+fn format_effective_addresses(operand1_val: u8, operand2_val: u8, (dest_address, src_address): (Option<u8>, Option<u8>)) -> String {
+    let resolved: Vec<String> = [(operand1_val, dest_address), (operand2_val, src_address)]
+        .iter()
+        .filter_map(|&(register, address)| address.map(|address| format!("[R{}] = M{}", register, address)))
+        .collect();
+    if resolved.is_empty() {
+        String::new()
+    } else {
+        format!("  ; {}", resolved.join(", "))
     }
+}
 
-    // If `--print-state` flag is set, print the final CPU state.
-    if print_usage {
-        println!("################### CPU STATE AFTER PROGRAM ###################");
-        println!("PC = {}", cpu.program_counter);
-        println!(
-            "reg1 = {}, reg2 = {}, reg3 = {}, reg4 = {}",
-            cpu.registers[0], cpu.registers[1], cpu.registers[2], cpu.registers[3]
+// Lists the stack from the most recently pushed byte (just above SP) down to the stack base,
+// one `M<ADDR>: <VALUE>` line per entry, for the `stack` command of `--step`.
+fn format_stack(cpu: &Cpu) -> String {
+    let first = cpu.stack_pointer as usize + 1;
+    let base = cpu.stack_base as usize;
+    if first > base {
+        return String::from("Stack is empty.\n");
+    }
+    let mut listing = format!("Stack ({} bytes, SP = {}):\n", base + 1 - first, cpu.stack_pointer);
+    for address in first..=base {
+        listing.push_str(&format!("  M{}: {}\n", address, cpu.data_memory()[address]));
+    }
+    listing
+}
+
+// Lists only the registers, flags and RAM cells that differ from the given earlier state,
+// e.g. `R0=7 ZF=1 M5=3`, for the terse `--trace-diff` output.
+fn format_state_changes(cpu: &Cpu, registers_before: &[u8], stack_pointer_before: u8, flags_before: u8, ram_before: &[u8]) -> String {
+    let mut changes = Vec::new();
+    for (index, (&before, &after)) in registers_before.iter().zip(cpu.registers.iter()).enumerate() {
+        if before != after {
+            changes.push(format!("R{}={}", index, after));
+        }
+    }
+    if stack_pointer_before != cpu.stack_pointer {
+        changes.push(format!("SP={}", cpu.stack_pointer));
+    }
+    for (flag, name) in [(FLAG_ZERO, "ZF"), (FLAG_CARRY, "CF"), (FLAG_SIGN, "SF"), (FLAG_OVERFLOW, "OF")] {
+        if (flags_before & flag) != (cpu.flags & flag) {
+            changes.push(format!("{}={}", name, u8::from(cpu.is_flag_set(flag))));
+        }
+    }
+    for (address, (&before, &after)) in ram_before.iter().zip(cpu.data_memory().iter()).enumerate() {
+        if before != after {
+            changes.push(format!("M{}={}", address, after));
+        }
+    }
+    changes.join(" ")
+}
+
+// Formats a byte both as unsigned and as a two's complement signed value, e.g. `255 (-1)`.
+// The signed reading is the one the Sign flag reports: bit 7 set means negative.
+fn format_unsigned_signed(value: u8) -> String {
+    format!("{} ({})", value, value as i8)
+}
+
+// Formats the CPU state (PC, registers, flags and a RAM snippet) under a banner with the given title.
+// Registers and RAM bytes are shown both unsigned and signed.
+// Used for `--print-state` after the run and by the `Dbg` instruction mid-run.
+fn format_state(cpu: &Cpu, title: &str) -> String {
+    let mut state = String::new();
+    state.push_str(&format!("################### {} ###################\n", title));
+    state.push_str(&format!("PC = {}\n", cpu.program_counter));
+    state.push_str(&format!("SP = {}\n", cpu.stack_pointer));
+    let registers: Vec<String> = cpu.registers.iter().enumerate().map(|(index, value)| format!("reg{} = {}", index + 1, format_unsigned_signed(*value))).collect();
+    state.push_str(&format!("{}\n", registers.join(", ")));
+    state.push_str(&format!("Flags (binary): {:08b}\n", cpu.flags));
+    state.push_str(&format!("  Zero Flag (ZF): {}\n", cpu.is_flag_set(FLAG_ZERO)));
+    state.push_str(&format!("  Carry Flag (CF): {}\n", cpu.is_flag_set(FLAG_CARRY)));
+    state.push_str(&format!("  Sign Flag (SF): {}\n", cpu.is_flag_set(FLAG_SIGN)));
+    state.push_str(&format!("  Overflow Flag (OF): {}\n", cpu.is_flag_set(FLAG_OVERFLOW)));
+    // Include a snippet of RAM contents for debugging.
+    let ram: Vec<String> = cpu.data_memory().iter().take(10).map(|&value| format_unsigned_signed(value)).collect();
+    state.push_str(&format!("RAM contents (first 10 bytes): [{}]\n", ram.join(", ")));
+    state
+}
+
+// Formats the final CPU state as a JSON object for tools to read: PC, SP, registers, flags,
+// instructions executed and only the non-zero RAM cells, keyed by address.
+fn format_state_json(cpu: &Cpu) -> String {
+    let registers: Vec<String> = cpu.registers.iter().map(|value| value.to_string()).collect();
+    let ram: Vec<String> = cpu.data_memory()
+        .iter()
+        .enumerate()
+        .filter(|&(_, &value)| value != 0)
+        .map(|(address, value)| format!("\"{}\": {}", address, value))
+        .collect();
+    let mut json = String::from("{\n");
+    json.push_str(&format!("  \"pc\": {},\n", cpu.program_counter));
+    json.push_str(&format!("  \"sp\": {},\n", cpu.stack_pointer));
+    json.push_str(&format!("  \"registers\": [{}],\n", registers.join(", ")));
+    json.push_str(&format!(
+        "  \"flags\": {{\"zero\": {}, \"carry\": {}, \"sign\": {}, \"overflow\": {}}},\n",
+        cpu.is_flag_set(FLAG_ZERO), cpu.is_flag_set(FLAG_CARRY), cpu.is_flag_set(FLAG_SIGN), cpu.is_flag_set(FLAG_OVERFLOW)
+    ));
+    json.push_str(&format!("  \"instructions_executed\": {},\n", cpu.instructions_executed));
+    json.push_str(&format!("  \"cycles\": {},\n", cpu.cycles));
+    json.push_str(&format!("  \"ram\": {{{}}}\n", ram.join(", ")));
+    json.push_str("}\n");
+    json
+}
+
+// Loads the program bytes into the CPU's program memory, refusing a program that doesn't fit.
+fn load_program(cpu: &mut Cpu, program: &[u8], base: u8) -> Result<(), String> {
+    let start = base as usize;
+    if start + program.len() > cpu.memory.len() {
+        return Err(format!("Program is {} bytes long, which exceeds the {}-byte program memory when loaded at address {}.", program.len(), cpu.memory.len(), base));
+    }
+    cpu.memory[start..start + program.len()].copy_from_slice(program);
+    Ok(())
+}
+
+// Runs the loaded program in the CPU.
+// It fetches, decodes, and executes instructions sequentially.
+// Returns a Result to indicate if any runtime errors occurred (e.g., unknown opcode, invalid address).
+fn run_program(cpu: &mut Cpu, program_size: usize, input: &mut dyn BufRead, output: &mut dyn Write, diagnostics: &mut dyn Write) -> Result<(), String> {
+    loop {
+        check_step_limit(cpu)?;
+        if !step(cpu, program_size, input, output, diagnostics)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Fails once the step limit is used up, so runs of non-terminating programs still return.
+fn check_step_limit(cpu: &Cpu) -> Result<(), String> {
+    if cpu.step_limit.is_some_and(|limit| cpu.instructions_executed >= limit) {
+        return Err(format!("Execution limit exceeded: {} instructions executed without halting. PC: {}", cpu.instructions_executed, cpu.program_counter));
+    }
+    Ok(())
+}
+
+// Reads one line from the input stream for the `In` instruction at `pc` and parses it as a byte.
+fn read_input_value(input: &mut dyn BufRead, pc: u8) -> Result<u8, String> {
+    let mut line = String::new();
+    let bytes_read = input
+        .read_line(&mut line)
+        .map_err(|e| format!("Runtime error: Failed to read input: {}. PC: {}", e, pc))?;
+    if bytes_read == 0 {
+        return Err(format!("Runtime error: No input left to read. PC: {}", pc));
+    }
+    line.trim()
+        .parse::<u8>()
+        .map_err(|e| format!("Runtime error: Invalid input '{}' (expected a number from 0 to 255): {}. PC: {}", line.trim(), e, pc))
+}
+
+// Reads the whole input stream for a program that declared with `.input` that it reads `count`
+// values, failing unless it holds exactly that many lines.
+fn read_declared_input(input: &mut dyn BufRead, count: usize) -> Result<String, String> {
+    let mut text = String::new();
+    input.read_to_string(&mut text).map_err(|e| format!("Failed to read input: {}", e))?;
+    let supplied = text.lines().count();
+    if supplied != count {
+        return Err(format!("The program declares {} input value(s) with .input, but {} were supplied.", count, supplied));
+    }
+    Ok(text)
+}
+
+// Fetches, decodes and executes the instruction at the PC.
+// Returns whether execution should continue: false after HLT or a `--stop-at-output` stop.
+fn step(cpu: &mut Cpu, program_size: usize, input: &mut dyn BufRead, output: &mut dyn Write, diagnostics: &mut dyn Write) -> Result<bool, String> {
+    // A correct program stops at HLT, so reaching the end of the program means it's missing one.
+    if (cpu.program_counter as usize) >= program_size {
+        return Err(format!("Runtime error: Program counter ran past end of program without HLT. PC: {}", cpu.program_counter));
+    }
+
+    // Check if there are enough bytes for a full 4-byte instruction
+    if (cpu.program_counter as usize) + (INSTRUCTION_SIZE as usize) > program_size {
+        return Err(format!("Program ended unexpectedly at PC {}. Incomplete instruction.", cpu.program_counter));
+    }
+
+    // Fetch the 4 bytes of the current instruction
+    let opcode_val = cpu.memory[cpu.program_counter as usize];
+    let mode_byte = cpu.memory[(cpu.program_counter + 1) as usize];
+    let operand1_val = cpu.memory[(cpu.program_counter + 2) as usize];
+    let operand2_val = cpu.memory[(cpu.program_counter + 3) as usize];
+
+    // Convert the opcode byte to an `Instructions` enum variant using the active opcode map.
+    // `decode` will return an error if the opcode is unknown.
+    let opcode = cpu.opcode_map.decode(opcode_val)?;
+
+    // Reject mode bits the instruction doesn't use, which only corrupt or hand-made bytecode can contain.
+    if !opcode.operand_format().accepts_mode_byte(mode_byte) {
+        return Err(format!("Runtime error: Invalid mode byte 0x{:02X} for {}. PC: {}", mode_byte, opcode.mnemonic(), cpu.program_counter));
+    }
+
+    // With `--detect-stuck`, fail as soon as the whole machine state repeats: the program can never leave that loop.
+    if cpu.stuck_detector.is_some() {
+        let state = StateFingerprint {
+            program_counter: cpu.program_counter,
+            stack_pointer: cpu.stack_pointer,
+            registers: cpu.registers.clone(),
+            flags: cpu.flags,
+            ram: cpu.data_memory().to_vec(),
+            pending_input: cpu.ports.iter().map(|port| port.input.len()).sum(),
+            input_reads: cpu.input_reads,
+        };
+        if cpu.stuck_detector.as_mut().is_some_and(|detector| detector.check(state)) {
+            return Err(format!("Infinite loop detected: PC {} was reached again with identical state.", cpu.program_counter));
+        }
+    }
+
+    cpu.instructions_executed += 1;
+    let cycles = instruction_cycles(opcode, mode_byte);
+    cpu.cycles += cycles;
+
+    // Count executions of this address for the block profile.
+    if let Some(counts) = cpu.pc_counts.as_mut() {
+        counts[cpu.program_counter as usize] += 1;
+    }
+
+    // Count executions of this instruction for `--profile`.
+    if let Some(counts) = cpu.instruction_counts.as_mut() {
+        counts[opcode.opcode() as usize] += 1;
+    }
+    if let Some(instruction_cycles) = cpu.instruction_cycles.as_mut() {
+        instruction_cycles[opcode.opcode() as usize] += cycles;
+    }
+
+    // Count it for the call stack it runs in, for `--flamegraph`.
+    if let Some(profile) = cpu.call_stacks.as_mut() {
+        *profile.counts.entry(profile.stack.clone()).or_insert(0) += 1;
+    }
+
+    // Record the decoded instruction in the execution path, up to the recording cap.
+    if let Some(path) = cpu.execution_path.as_mut() {
+        if path.len() < MAX_RECORDED_PATH {
+            path.push(opcode);
+        }
+    }
+
+    // If the instruction is HLT, print message and terminate execution.
+    if opcode == Instructions::HLT {
+        if cpu.trace && cpu.in_trace_range(cpu.program_counter) {
+            writeln!(diagnostics, "[PC {}] HLT", cpu.program_counter)
+                .map_err(|e| format!("Runtime error: Failed to write diagnostics: {}. PC: {}", e, cpu.program_counter))?;
+        }
+        if !cpu.quiet {
+            writeln!(output, "Halted.")
+                .map_err(|e| format!("Runtime error: Failed to write output: {}. PC: {}", e, cpu.program_counter))?;
+        }
+        return Ok(false);
+    }
+
+    // Decode operand types from the `mode_byte`:
+    // Bit 0 (0b01) controls dest_type: 1 means Memory, 0 means Register.
+    // Bit 1 (0b10) controls src_type: 1 means Memory, 0 means Register.
+    // Bits 3 and 4 (`DEST_INDIRECT`, `SRC_INDIRECT`) instead make them register-indirect.
+    let dest_type = OperandType::from_mode_byte(mode_byte, 0b01, DEST_INDIRECT);
+    let src_type = OperandType::from_mode_byte(mode_byte, 0b10, SRC_INDIRECT);
+
+    // Remember the state before executing so `--trace-diff` can report what changed.
+    let state_before = if cpu.trace_diff && cpu.in_trace_range(cpu.program_counter) { Some((cpu.registers.clone(), cpu.stack_pointer, cpu.flags, cpu.data_memory().to_vec())) } else { None };
+    let pc_before = cpu.program_counter;
+    // The RAM addresses `[R#]` operands refer to, taken before the instruction can change the registers.
+    let effective_addresses = indirect_addresses(cpu, opcode, mode_byte, operand1_val, operand2_val);
+
+    // `In` reads its value here, since only `step` has access to the input stream.
+    if opcode == Instructions::In {
+        cpu.input_value = Some(read_input_value(input, cpu.program_counter)?);
+        cpu.input_reads += 1;
+    }
+
+    // Execute the decoded instruction with its operands and types.
+    // Errors from `execute_instruction` (e.g., invalid register/memory access) are propagated.
+    let result = execute_instruction(
+        cpu,
+        opcode,
+        mode_byte,
+        dest_type,
+        operand1_val,
+        src_type,
+        operand2_val,
+    );
+
+    // Pass on `Dbg` dumps and `--watch` lines, including those from before an instruction failed.
+    let write_diagnostics_error = |e: std::io::Error| format!("Runtime error: Failed to write diagnostics: {}. PC: {}", e, pc_before);
+    if !cpu.pending_diagnostics.is_empty() {
+        diagnostics.write_all(cpu.pending_diagnostics.as_bytes()).map_err(write_diagnostics_error)?;
+        cpu.pending_diagnostics.clear();
+    }
+    result?;
+
+    // Follow subroutine calls for `--flamegraph`: Call has already jumped to the subroutine's entry.
+    // A Ret without a matching Call (e.g. after pushing an address by hand) leaves the stack as it is.
+    if let Some(profile) = cpu.call_stacks.as_mut() {
+        match opcode {
+            Instructions::Call => profile.stack.push(cpu.program_counter),
+            Instructions::Ret => {
+                profile.stack.pop();
+            }
+            _ => {}
+        }
+    }
+
+    // Pass on anything `Out` printed, flushing so it appears before the program continues.
+    if !cpu.pending_output.is_empty() {
+        output
+            .write_all(&cpu.pending_output)
+            .and_then(|_| output.flush())
+            .map_err(|e| format!("Runtime error: Failed to write output: {}. PC: {}", e, pc_before))?;
+        cpu.pending_output.clear();
+    }
+
+    if let Some((registers_before, stack_pointer_before, flags_before, ram_before)) = state_before {
+        let line = format!(
+            "[PC {}] {}  {}",
+            pc_before,
+            format_instruction(opcode, mode_byte, operand1_val, operand2_val),
+            format_state_changes(cpu, &registers_before, stack_pointer_before, flags_before, &ram_before)
         );
-        println!("Flags (binary): {:08b}", cpu.flags);
-        println!("  Zero Flag (ZF): {}", cpu.is_flag_set(FLAG_ZERO));
-        println!("  Carry Flag (CF): {}", cpu.is_flag_set(FLAG_CARRY));
-        // Print a snippet of RAM contents for debugging.
-        println!("RAM contents (first 10 bytes): {:?}", &cpu.ram[0..10]);
+        writeln!(diagnostics, "{}", line.trim_end()).map_err(write_diagnostics_error)?;
+    }
+
+    // For jump instructions, PC is handled within execute_instruction.
+    // For all other instructions, we advance PC by INSTRUCTION_SIZE.
+    match opcode {
+        Instructions::JmpAddr | Instructions::JmpEq | Instructions::JmpNe | Instructions::JmpGt
+        | Instructions::Call | Instructions::Ret
+        | Instructions::JmpLt | Instructions::JmpGe | Instructions::JmpLe
+        | Instructions::JmpC | Instructions::JmpNc | Instructions::JmpRel | Instructions::Loop => {
+            // PC was already set/incremented inside execute_instruction. Do nothing here.
+        },
+        _ => {
+            // For all non-jump instructions, advance PC to the next instruction.
+            cpu.program_counter = cpu.next_instruction_address()?;
+        }
+    }
+
+    // With `--trace`, show the instruction and what it left behind, after the PC has moved on.
+    if cpu.trace && cpu.in_trace_range(pc_before) {
+        writeln!(
+            diagnostics,
+            "[PC {}] {}{}  -> {}",
+            pc_before,
+            format_instruction(opcode, mode_byte, operand1_val, operand2_val),
+            format_effective_addresses(operand1_val, operand2_val, effective_addresses),
+            format_trace_effect(cpu, opcode, mode_byte, operand1_val, effective_addresses.0)
+        ).map_err(write_diagnostics_error)?;
+    }
+
+    // With `--stop-at-output`, pause right after the first output so the state that produced it can be inspected.
+    if cpu.stop_at_output && opcode.produces_output() {
+        write!(output, "{}", format_state(cpu, &format!("STOPPED AFTER FIRST OUTPUT AT PC {}", pc_before)))
+            .map_err(|e| format!("Runtime error: Failed to write output: {}. PC: {}", e, pc_before))?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+impl Cpu {
+    // Creates a CPU with all registers and memory set to 0 and default settings, as if run with no flags.
+    pub fn new() -> Cpu {
+        Cpu::with_options(&RunOptions::default())
+    }
+
+    // Like `new`, but with the memory size and register count of the given machine.
+    pub fn with_machine(machine: MachineConfig) -> Cpu {
+        Cpu::with_options(&RunOptions { machine, ..RunOptions::default() })
+    }
+
+    // Creates a CPU configured by `options`. Port input and RAM preloads are not applied here.
+    fn with_options(options: &RunOptions) -> Cpu {
+        Cpu {
+            registers: vec![0; options.machine.register_count],
+            memory: vec![0; options.machine.memory_size], // Program memory
+            ram: vec![0; options.machine.memory_size],    // Data memory
+            program_counter: 0,
+            program_size: 0,
+            stack_pointer: (options.machine.memory_size - 1) as u8, // The stack grows down from the top of data memory.
+            stack_base: (options.machine.memory_size - 1) as u8,
+            stack_limit: 0,
+            flags: 0, // Initialize flags to 0
+            execution_path: if options.record_path { Some(Vec::new()) } else { None },
+            pc_counts: if options.hot_cfg { Some(vec![0; options.machine.memory_size]) } else { None },
+            instruction_counts: if options.profile || options.stats_csv.is_some() { Some(vec![0; Instructions::ALL.len()]) } else { None },
+            instruction_cycles: if options.profile || options.stats_csv.is_some() { Some(vec![0; Instructions::ALL.len()]) } else { None },
+            overflow_events: if options.report_overflows { Some(Vec::new()) } else { None },
+            ports: vec![Port::default(); PORT_COUNT],
+            trace_diff: options.trace_diff,
+            trace: options.trace,
+            trace_range: options.trace_range,
+            opcode_map: options.opcode_map.clone(),
+            stop_at_output: options.stop_at_output,
+            stuck_detector: if options.detect_stuck { Some(StuckDetector::new()) } else { None },
+            von_neumann: options.von_neumann,
+            data_high_water: 0,
+            quiet: false,
+            step_limit: options.max_cycles,
+            inverted_borrow: options.inverted_borrow,
+            first_writes: if options.first_touch { Some(FirstWrites { registers: vec![None; options.machine.register_count], ram: vec![None; options.machine.memory_size] }) } else { None },
+            pending_output: Vec::new(),
+            pending_diagnostics: String::new(),
+            input_value: None,
+            input_reads: 0,
+            instructions_executed: 0,
+            cycles: 0,
+            watched: options.watch.clone(),
+            extension_handler: options.extension_handler,
+            call_stacks: if options.flamegraph.is_some() { Some(CallStackProfile::default()) } else { None },
+        }
+    }
+
+    // Copies a program into program memory starting at address 0; it runs until the PC reaches its end.
+    // Fails, leaving the CPU unchanged, if the program is larger than program memory.
+    pub fn load(&mut self, program: &[u8]) -> Result<(), String> {
+        self.load_at(program, 0)
+    }
+
+    // Like `load`, but copies the program to `base` and starts execution there, for programs
+    // assembled with `--base`. The program still ends where its last byte is.
+    fn load_at(&mut self, program: &[u8], base: u8) -> Result<(), String> {
+        load_program(self, program, base)?;
+        self.program_counter = base;
+        self.program_size = base as usize + program.len();
+        Ok(())
+    }
+
+    // Executes the instruction at the PC, reading `In` values from `input`, writing `Out` and
+    // the "Halted." message to `output` and `Dbg` dumps and trace lines to `diagnostics`,
+    // e.g. `std::io::stdin().lock()`, `std::io::stdout()` and `std::io::stderr()`.
+    // Returns false once the program has halted, and an error if it ran past its end without halting.
+    pub fn step(&mut self, input: &mut impl BufRead, output: &mut impl Write, diagnostics: &mut impl Write) -> Result<bool, String> {
+        step(self, self.program_size, input, output, diagnostics)
+    }
+
+    // Executes instructions until the program halts or an error occurs, with I/O as for `step`.
+    // Returns the total number of instructions executed by this CPU.
+    pub fn run(&mut self, input: &mut impl BufRead, output: &mut impl Write, diagnostics: &mut impl Write) -> Result<u64, String> {
+        run_program(self, self.program_size, input, output, diagnostics)?;
+        Ok(self.instructions_executed)
+    }
+
+    // Number of instructions executed so far, counting each executed jump once.
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
+
+    // Value of register R<index>. Panics if `index` is not below the machine's register count.
+    pub fn register(&self, index: usize) -> u8 {
+        self.registers[index]
+    }
+
+    // Byte at `address` in data memory (program memory in von Neumann mode).
+    pub fn ram(&self, address: u8) -> u8 {
+        self.data_memory()[address as usize]
+    }
+
+    // Sets register R<index>, e.g. from an extension handler. Panics like `register`.
+    pub fn set_register(&mut self, index: usize, value: u8) {
+        self.registers[index] = value;
+    }
+
+    // Sets the byte at `address` in data memory, e.g. from an extension handler. Panics like `ram`.
+    pub fn set_ram(&mut self, address: u8, value: u8) {
+        self.data_memory_mut()[address as usize] = value;
+    }
+
+    // Registers the function the `Ext` instruction runs; see `ExtensionHandler`.
+    pub fn set_extension_handler(&mut self, handler: ExtensionHandler) {
+        self.extension_handler = Some(handler);
+    }
+
+    // Status flags, as a bit set of the `FLAG_*` bits (bit 0 Zero, 1 Carry, 2 Sign, 3 Overflow).
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    // Address of the next instruction to execute.
+    pub fn pc(&self) -> u8 {
+        self.program_counter
+    }
+
+    // Captures the registers, memories, PC, SP and flags, e.g. as a checkpoint to roll back to.
+    pub fn snapshot(&self) -> CpuState {
+        CpuState {
+            registers: self.registers.clone(),
+            memory: self.memory.clone(),
+            ram: self.ram.clone(),
+            program_counter: self.program_counter,
+            stack_pointer: self.stack_pointer,
+            flags: self.flags,
+        }
+    }
+
+    // Puts the CPU back into a state taken with `snapshot`. The state should come from a CPU of the
+    // same machine configuration. Settings, I/O ports and counters such as `instructions_executed`
+    // are not part of the state and are left as they are.
+    pub fn restore(&mut self, state: &CpuState) {
+        self.registers.clone_from(&state.registers);
+        self.memory.clone_from(&state.memory);
+        self.ram.clone_from(&state.ram);
+        self.program_counter = state.program_counter;
+        self.stack_pointer = state.stack_pointer;
+        self.flags = state.flags;
+    }
+}
+
+// A copy of the CPU state taken by `Cpu::snapshot`, which `Cpu::restore` can return to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuState {
+    pub registers: Vec<u8>,   // General-purpose register values.
+    pub memory: Vec<u8>,      // Program memory, including any code the program modified in von Neumann mode.
+    pub ram: Vec<u8>,         // Data memory.
+    pub program_counter: u8,  // Address of the next instruction to execute.
+    pub stack_pointer: u8,    // Address of the next free stack slot.
+    pub flags: u8,            // Status flags, as a bit set of the `FLAG_*` bits.
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Cpu::new()
+    }
+}
+
+// Builds a CPU for the given options with the program loaded and ports and RAM preloaded,
+// ready to run. Fails if a RAM preload doesn't fit in data memory.
+fn prepare_cpu(program: &[u8], options: &RunOptions) -> Result<Cpu, String> {
+    let mut cpu = Cpu::with_options(options);
+
+    // Bind the host-provided input bytes to their ports.
+    for (port, bytes) in &options.port_inputs {
+        cpu.ports[*port as usize].input.extend(bytes.iter().copied());
+    }
+
+    // Load the provided program into the CPU's memory.
+    cpu.load_at(program, options.base)?;
+
+    // Preload RAM with the host-provided bytes, refusing to run if any would fall outside RAM.
+    // This happens after loading so that in von Neumann mode preloaded data lands on top of the code.
+    for (address, bytes) in &options.ram_preload {
+        let start = *address as usize;
+        let data_memory = cpu.data_memory_mut();
+        if start + bytes.len() > data_memory.len() {
+            return Err(format!(
+                "RAM preload of {} bytes at address {} exceeds RAM size {}.",
+                bytes.len(), start, data_memory.len()
+            ));
+        }
+        data_memory[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+    Ok(cpu)
+}
+
+// Everything a finished run can be observed to have done, as captured by `run_silently`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub error: Option<String>,       // The runtime error the run stopped with, if any.
+    pub registers: Vec<u8>,          // Final register values.
+    pub ram: Vec<u8>,                // Final contents of data memory.
+    pub port_outputs: Vec<Vec<u8>>,  // Bytes written to each I/O port, indexed by port number.
+    pub output: Vec<u8>,             // Everything the program printed with `Out`.
+}
+
+// Runs a program without printing anything, stopping after `step_limit` instructions, and
+// returns how the run ended and what it printed. Used to replay programs for `--reduce` and `--equiv`.
+// Fails only if the CPU can't be set up, e.g. when a RAM preload doesn't fit.
+pub fn run_silently(program: &[u8], options: &RunOptions, step_limit: u64) -> Result<RunOutcome, String> {
+    // Only the options that change how the program executes matter here; all reporting is off.
+    let options = RunOptions { trace_diff: false, trace: false, stop_at_output: false, ..options.clone() };
+    let mut cpu = prepare_cpu(program, &options)?;
+    let program_size = cpu.program_size;
+    cpu.quiet = true;
+    cpu.step_limit = Some(step_limit);
+    // Quiet mode leaves out "Halted.", so only what the program itself printed is captured.
+    let mut output = Vec::new();
+    let error = run_program(&mut cpu, program_size, &mut std::io::empty(), &mut output, &mut std::io::sink()).err();
+    Ok(RunOutcome {
+        error,
+        registers: cpu.registers.clone(),
+        ram: cpu.data_memory().to_vec(),
+        port_outputs: cpu.ports.iter().map(|port| port.output.clone()).collect(),
+        output,
+    })
+}
+
+// Runs a program, reading values for `In` from `input` and writing anything it prints with
+// `Out` to `output`, then prints the reports selected in `options`.
+// Returns the error the run stopped with, if any; the reports are printed either way.
+pub fn run_emulation(program_vector: Vec<u8>, options: &RunOptions, input: &mut impl BufRead, output: &mut impl Write, diagnostics: &mut impl Write) -> Result<(), String> {
+    let program = &program_vector[..];
+    let mut cpu = prepare_cpu(program, options)?;
+    let program_size = cpu.program_size; // The part of the program that fits in memory.
+
+    // With a declared `.input` count, read all input first so a mismatch stops the run before it starts.
+    let declared_input = match options.input_count {
+        Some(count) => Some(read_declared_input(input, count)?),
+        None => None,
+    };
+
+    // Run the program, keeping any emulation error to return once the reports are printed.
+    let result = match &declared_input {
+        Some(text) => run_program(&mut cpu, program_size, &mut text.as_bytes(), output, diagnostics),
+        None => run_program(&mut cpu, program_size, input, output, diagnostics),
+    };
+    let mut report = String::new();
+
+    // If `--json` or `--print-state` is set, print the final CPU state, as JSON if both are given.
+    if options.json {
+        report.push_str(&format_state_json(&cpu));
+    } else if options.print_state {
+        report.push_str(&format_state(&cpu, "CPU STATE AFTER PROGRAM"));
+        report.push_str(&format!("Instructions executed: {}\n", cpu.instructions_executed));
+        report.push_str(&format!("Cycles: {}\n", cpu.cycles));
+    }
+
+    // If `--dump-code` is set, print program memory, which may have been modified in von Neumann mode.
+    // The range is cut off at the end of memory, which is smaller than 256 bytes on some machines.
+    if let Some((start, end)) = options.dump_code {
+        let end = end.min((cpu.memory.len() - 1) as u8);
+        report.push_str(&format!("Code memory {}-{}:\n", start, end));
+        if start <= end {
+            report.push_str(&hex_grid(&cpu.memory, start, end));
+        }
+    }
+
+    // Print everything the program wrote to I/O ports.
+    for (port, binding) in cpu.ports.iter().enumerate() {
+        if !binding.output.is_empty() {
+            report.push_str(&format!("Port {} output: {:?}\n", port, binding.output));
+        }
+    }
+
+    // If `--first-touch` flag is set, print which instruction first wrote each written location.
+    if let Some(first_writes) = &cpu.first_writes {
+        report.push_str("First writes:\n");
+        let registers = first_writes.registers.iter().enumerate().map(|(index, pc)| (format!("R{}", index), pc));
+        let ram = first_writes.ram.iter().enumerate().map(|(address, pc)| (format!("M{}", address), pc));
+        let mut any_written = false;
+        for (location, pc) in registers.chain(ram) {
+            if let Some(pc) = pc {
+                report.push_str(&format!("  {}: PC {}\n", location, pc));
+                any_written = true;
+            }
+        }
+        if !any_written {
+            report.push_str("  (nothing written)\n");
+        }
+    }
+
+    // If `--report-overflows` flag is set, print every arithmetic overflow that occurred.
+    if let Some(events) = &cpu.overflow_events {
+        report.push_str(&format!("Overflow report: {} event(s)\n", events.len()));
+        for event in events {
+            let kind = match (event.carry, event.signed_overflow) {
+                (true, true) => "unsigned carry, signed overflow",
+                (true, false) => "unsigned carry",
+                _ => "signed overflow",
+            };
+            report.push_str(&format!(
+                "  PC {}: {} {} {} -> {} ({})\n",
+                event.pc, event.instruction.mnemonic(), event.operands.0, event.operands.1, event.result, kind
+            ));
+        }
+    }
+
+    // If `--budget` is set, check the code size and the data high-water mark against their limits.
+    if options.code_budget.is_some() || options.data_budget.is_some() {
+        report.push_str(&budget_report(program.len(), cpu.data_high_water, options.code_budget, options.data_budget));
+    }
+
+    // If `--profile` flag is set, print how often each instruction ran, most frequent first.
+    if let (true, Some(counts), Some(cycles)) = (options.profile, &cpu.instruction_counts, &cpu.instruction_cycles) {
+        report.push_str(&instruction_profile(counts, cycles));
+    }
+
+
+    // If `--hot-cfg` flag is set, print the profiled control-flow graph.
+    if let Some(counts) = &cpu.pc_counts {
+        // The graph uses the addresses the program ran at, so one loaded at `--base` is placed there.
+        let mut image = vec![0; options.base as usize];
+        image.extend_from_slice(program);
+        report.push_str(&hot_cfg_graph(&image, options.base as usize, &cpu.opcode_map, counts));
+    }
+
+    // If `--path` flag is set, print the executed instructions in order.
+    if let Some(path) = &cpu.execution_path {
+        let mnemonics: Vec<&str> = path.iter().map(|instruction| instruction.mnemonic()).collect();
+        report.push_str(&format!("Execution path: {}\n", mnemonics.join(" -> ")));
+        if path.len() == MAX_RECORDED_PATH {
+            report.push_str(&format!("(path truncated after {} instructions)\n", MAX_RECORDED_PATH));
+        }
+    }
+    output.write_all(report.as_bytes()).map_err(|e| format!("Failed to write output: {}", e))?;
+
+    // If `--stats-csv` is set, save the execution counts as a CSV file for spreadsheets.
+    if let (Some(path), Some(counts), Some(cycles)) = (&options.stats_csv, &cpu.instruction_counts, &cpu.instruction_cycles) {
+        std::fs::write(path, instruction_stats_csv(counts, cycles)).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    }
+
+    // If `--flamegraph` is set, save the counts per call stack for flamegraph tools.
+    if let (Some(path), Some(profile)) = (&options.flamegraph, &cpu.call_stacks) {
+        std::fs::write(path, folded_stacks(profile, &options.labels)).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    }
+    result
+}
+
+// Runs a program one instruction at a time for `--step`. Before each instruction, shows its PC,
+// disassembly and raw bytes and waits for a line on `input`: Enter executes it and prints the
+// resulting CPU state, while `q` (or the end of input) stops. `In` reads from the same `input`.
+pub fn run_stepping(program_vector: Vec<u8>, options: &RunOptions, input: &mut impl BufRead, output: &mut impl Write, diagnostics: &mut impl Write) -> Result<(), String> {
+    let program = &program_vector[..];
+    let mut cpu = prepare_cpu(program, options)?;
+    let program_size = cpu.program_size;
+    let write_error = |e: std::io::Error| format!("Failed to write output: {}", e);
+
+    // Running past the end is reported by `step`, without prompting for the missing instruction.
+    while (cpu.program_counter as usize) < program_size {
+        let pc = cpu.program_counter as usize;
+        let bytes = &cpu.memory[pc..(pc + INSTRUCTION_SIZE as usize).min(cpu.memory.len())];
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(output, "[PC {}] {}   ({})\nEnter = step, run-to <ADDR>, stack, q = quit> ", pc, disassemble_bytes(bytes, &cpu.opcode_map), hex.join(" "))
+            .and_then(|_| output.flush())
+            .map_err(write_error)?;
+
+        let mut command = String::new();
+        let bytes_read = input.read_line(&mut command).map_err(|e| format!("Failed to read input: {}", e))?;
+        if bytes_read == 0 || command.trim().eq_ignore_ascii_case("q") {
+            writeln!(output).map_err(write_error)?;
+            return Ok(());
+        }
+
+        // `stack` shows the stack without executing anything, then prompts again.
+        if command.trim() == "stack" {
+            write!(output, "{}", format_stack(&cpu)).map_err(write_error)?;
+            continue;
+        }
+
+        // `run-to <ADDR>` executes without prompting until the PC reaches the address, like a one-shot
+        // breakpoint, and then shows the state once. `--max-cycles` still stops a run that never gets there.
+        if let Some(target_str) = command.trim().strip_prefix("run-to") {
+            let target = match crate::asm::parse_number(target_str.trim()) {
+                Ok(target) => target,
+                Err(e) => {
+                    writeln!(output, "Invalid address '{}': {}", target_str.trim(), e).map_err(write_error)?;
+                    continue;
+                }
+            };
+            loop {
+                check_step_limit(&cpu)?;
+                if !step(&mut cpu, program_size, input, output, diagnostics)? {
+                    write!(output, "{}", format_state(&cpu, "CPU STATE AFTER HALT")).map_err(write_error)?;
+                    return Ok(());
+                }
+                if cpu.program_counter == target {
+                    break;
+                }
+            }
+            write!(output, "{}", format_state(&cpu, &format!("CPU STATE AT PC {}", target))).map_err(write_error)?;
+            continue;
+        }
+
+        let running = step(&mut cpu, program_size, input, output, diagnostics)?;
+        write!(output, "{}", format_state(&cpu, &format!("CPU STATE AFTER PC {}", pc))).map_err(write_error)?;
+        if !running {
+            return Ok(());
+        }
+    }
+    step(&mut cpu, program_size, input, output, diagnostics).map(|_| ())
+}
+
+// The per-thread part of the machine for `run_multi`: each thread has its own program,
+// registers, flags, PC and stack, while RAM and I/O ports are shared by all threads.
+// The stacks are consecutive `THREAD_STACK_SIZE`-byte slices of RAM, growing down from the top:
+// thread 0's stack holds the top 16 bytes, thread 1's the 16 bytes below, and so on.
+struct ThreadContext {
+    registers: Vec<u8>,
+    flags: u8,
+    program_counter: u8,
+    stack_pointer: u8,
+    stack_base: u8,
+    stack_limit: u8,
+    memory: Vec<u8>,
+    program_size: usize,
+    finished: bool,
+}
+
+impl ThreadContext {
+    // Exchanges this thread's state with the state loaded in the CPU: called once to switch the
+    // thread in and once more to switch it back out. Registers and code are swapped rather than
+    // copied, so a switch costs the same however large memory is; the shared RAM stays in the CPU.
+    fn swap_with(&mut self, cpu: &mut Cpu) {
+        std::mem::swap(&mut self.registers, &mut cpu.registers);
+        std::mem::swap(&mut self.memory, &mut cpu.memory);
+        std::mem::swap(&mut self.flags, &mut cpu.flags);
+        std::mem::swap(&mut self.program_counter, &mut cpu.program_counter);
+        std::mem::swap(&mut self.program_size, &mut cpu.program_size);
+        std::mem::swap(&mut self.stack_pointer, &mut cpu.stack_pointer);
+        std::mem::swap(&mut self.stack_base, &mut cpu.stack_base);
+        std::mem::swap(&mut self.stack_limit, &mut cpu.stack_limit);
+    }
+}
+
+// Runs several programs as threads over shared RAM with a deterministic round-robin scheduler:
+// each unfinished thread in turn executes up to `quantum` instructions, starting with the first
+// program. A thread finishes when it halts or hits a runtime error, such as running off the end
+// of its program without HLT, which doesn't stop the other threads. Once all threads have
+// finished and the reports are written, the errors of the threads that failed are returned.
+// `--max-cycles` limits the instructions of all threads together, and a declared `.input` count
+// (`options.input_count`) is checked against the input before any thread starts.
+pub fn run_multi(programs: Vec<Vec<u8>>, quantum: usize, options: &RunOptions, input: &mut impl BufRead, output: &mut impl Write, diagnostics: &mut impl Write) -> Result<(), String> {
+    if options.von_neumann {
+        return Err(String::from("Threads need separate code and data memory, so they can't be combined with --von-neumann."));
+    }
+    if options.base != 0 {
+        return Err(String::from("Threads are loaded at address 0, so they can't be combined with --base."));
+    }
+    if quantum == 0 {
+        return Err(String::from("The scheduling quantum must be at least one instruction."));
+    }
+    // The CPU holds the shared RAM and ports; thread contexts are swapped in and out of it.
+    let mut cpu = prepare_cpu(&[], options)?;
+    // An exact state repeat in one thread says nothing about the others, so stuck detection is off.
+    cpu.stuck_detector = None;
+
+    // Every thread's stack slice must fit below the top of RAM; the lowest limit may be address 0.
+    let top = cpu.stack_base as usize;
+    if programs.len() * THREAD_STACK_SIZE > top {
+        return Err(format!(
+            "{} threads need {} bytes of RAM for their stacks, but only {} bytes are available.",
+            programs.len(), programs.len() * THREAD_STACK_SIZE, top
+        ));
+    }
+
+    let memory_size = cpu.memory.len();
+    let mut threads = programs
+        .iter()
+        .enumerate()
+        .map(|(index, program)| {
+            load_program(&mut cpu, program, 0).map_err(|e| format!("Thread {}: {}", index, e))?;
+            let stack_base = (top - index * THREAD_STACK_SIZE) as u8;
+            Ok(ThreadContext {
+                registers: vec![0; cpu.registers.len()],
+                flags: 0,
+                program_counter: 0,
+                stack_pointer: stack_base,
+                stack_base,
+                stack_limit: stack_base - THREAD_STACK_SIZE as u8,
+                memory: std::mem::replace(&mut cpu.memory, vec![0; memory_size]),
+                program_size: program.len(),
+                finished: false,
+            })
+        })
+        .collect::<Result<Vec<ThreadContext>, String>>()?;
+
+    // With a declared `.input` count, read all input first so a mismatch stops the run before any thread starts.
+    let declared_input = match options.input_count {
+        Some(count) => Some(read_declared_input(input, count)?),
+        None => None,
+    };
+    let mut declared_bytes = declared_input.as_deref().map(str::as_bytes);
+    let input: &mut dyn BufRead = match &mut declared_bytes {
+        Some(bytes) => bytes,
+        None => input,
+    };
+
+    let mut errors = Vec::new();
+    'scheduler: while threads.iter().any(|thread| !thread.finished) {
+        for (index, thread) in threads.iter_mut().enumerate().filter(|(_, thread)| !thread.finished) {
+            thread.swap_with(&mut cpu);
+            for _ in 0..quantum {
+                // The step limit counts the instructions of all threads together, and stops them all.
+                if let Err(e) = check_step_limit(&cpu) {
+                    thread.swap_with(&mut cpu);
+                    errors.push(e);
+                    break 'scheduler;
+                }
+                let program_size = cpu.program_size;
+                match step(&mut cpu, program_size, input, output, diagnostics) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        thread.finished = true;
+                        break;
+                    }
+                    Err(e) => {
+                        errors.push(format!("Thread {}: {}", index, e));
+                        thread.finished = true;
+                        break;
+                    }
+                }
+            }
+            thread.swap_with(&mut cpu);
+        }
+    }
+
+    // If `--print-state` flag is set, print each thread's final registers alongside the shared RAM.
+    let mut report = String::new();
+    if options.print_state {
+        for (index, thread) in threads.iter_mut().enumerate() {
+            thread.swap_with(&mut cpu);
+            report.push_str(&format_state(&cpu, &format!("THREAD {} STATE AFTER PROGRAM", index)));
+            thread.swap_with(&mut cpu);
+        }
+    }
+
+    // Print everything the threads wrote to I/O ports.
+    for (port, binding) in cpu.ports.iter().enumerate() {
+        if !binding.output.is_empty() {
+            report.push_str(&format!("Port {} output: {:?}\n", port, binding.output));
+        }
+    }
+    output.write_all(report.as_bytes()).map_err(|e| format!("Failed to write output: {}", e))?;
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::lexer;
+
+    // Assembles source for the default machine, panicking on assembly errors.
+    fn assemble(source: &str) -> Vec<u8> {
+        lexer(source, &OpcodeMap::default(), &MachineConfig::default(), None).unwrap()
+    }
+
+    // Runs a program to completion and returns the CPU along with its diagnostics output.
+    fn run_with_diagnostics(source: &str, trace: bool) -> (Cpu, String) {
+        let mut cpu = Cpu::new();
+        cpu.trace = trace;
+        cpu.load(&assemble(source)).unwrap();
+        let mut diagnostics = Vec::new();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut diagnostics).unwrap();
+        (cpu, String::from_utf8(diagnostics).unwrap())
+    }
+
+    #[test]
+    fn run_to_pauses_at_the_target_address() {
+        let program = assemble("Inc R0\nInc R0\nInc R0\nInc R1\nHLT\n");
+        let mut commands = "run-to 12\nq\n".as_bytes();
+        let mut output = Vec::new();
+        run_stepping(program, &RunOptions::default(), &mut commands, &mut output, &mut std::io::sink()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let paused = output.split("CPU STATE AT PC 12").nth(1).expect(&output);
+        assert!(paused.contains("reg1 = 3 (3), reg2 = 0 (0)"), "{}", output);
+        assert!(paused.contains("[PC 12] Inc R1"), "{}", output);
+        assert_eq!(output.matches("Enter = step").count(), 2);
+    }
+
+    #[test]
+    fn stack_command_shows_the_return_addresses() {
+        let program = assemble("Call outer\nHLT\nouter: Call inner\nRet\ninner: Inc R0\nRet\n");
+        let mut commands = "\n\nstack\nq\n".as_bytes();
+        let mut output = Vec::new();
+        run_stepping(program, &RunOptions::default(), &mut commands, &mut output, &mut std::io::sink()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Stack (2 bytes, SP = 253):\n  M254: 12\n  M255: 4\n"), "{}", output);
+    }
+
+    #[test]
+    fn declared_input_count_is_checked_before_the_run() {
+        let options = RunOptions { input_count: Some(2), ..RunOptions::default() };
+        let mut output = Vec::new();
+        let error = run_emulation(assemble("In R0\nOut R0\nIn R1\nHLT\n"), &options, &mut "7\n".as_bytes(), &mut output, &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "The program declares 2 input value(s) with .input, but 1 were supplied.");
+        assert!(output.is_empty(), "the program must not start");
+
+        run_emulation(assemble("In R0\nIn R1\nHLT\n"), &options, &mut "7\n8\n".as_bytes(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+    }
+
+    #[test]
+    fn program_assembled_with_a_base_runs_from_there() {
+        let program = crate::asm::assemble_program("JmpAddr end\nInc R0\nend: Out R0\nHLT\n", &OpcodeMap::default(), &MachineConfig::default(), 16, None).unwrap().bytes;
+        let options = RunOptions { base: 16, ..RunOptions::default() };
+        let mut output = Vec::new();
+        run_emulation(program, &options, &mut std::io::empty(), &mut output, &mut std::io::sink()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "0\nHalted.\n");
+    }
+
+    #[test]
+    fn hexdump_lists_bytes_next_to_the_disassembly() {
+        let program = assemble("MovImm R0 5\nHLT\n");
+        let listing = hexdump(&program, 0, &OpcodeMap::default());
+        assert_eq!(listing.lines().next(), Some("0000: 01 00 00 05   MovImm R0 5"));
+        let listing = hexdump(&program, 16, &OpcodeMap::default());
+        assert_eq!(listing.lines().map(|line| &line[..6]).collect::<Vec<_>>(), ["0016: ", "0020: "]);
+    }
+
+    // Runs two copies of a racy read-modify-write of M0 as threads and returns the final M0.
+    fn racy_increment(quantum: usize) -> u8 {
+        let program = assemble("Mov R0 M0\nInc R0\nMov M0 R0\nHLT\n");
+        let options = RunOptions { print_state: true, ..RunOptions::default() };
+        let mut output = Vec::new();
+        run_multi(vec![program.clone(), program], quantum, &options, &mut std::io::empty(), &mut output, &mut std::io::sink()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let ram = output.lines().find(|line| line.starts_with("RAM contents")).unwrap();
+        ram.split('[').nth(1).unwrap().split(' ').next().unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn threads_interleave_deterministically() {
+        assert_eq!(racy_increment(1), 1); // Both threads read 0 before either writes: one update is lost.
+        assert_eq!(racy_increment(4), 2); // Each thread runs to HLT in one time slice.
+    }
+
+    #[test]
+    fn threads_check_the_declared_input_count_before_running() {
+        let options = RunOptions { input_count: Some(2), ..RunOptions::default() };
+        let program = assemble("In R0\nOut R0\nIn R0\nHLT\n");
+        let mut output = Vec::new();
+        let error = run_multi(vec![program], 1, &options, &mut "7\n".as_bytes(), &mut output, &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "The program declares 2 input value(s) with .input, but 1 were supplied.");
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn stats_csv_counts_each_executed_instruction() {
+        let mut cpu = Cpu::with_options(&RunOptions { stats_csv: Some(String::new()), ..RunOptions::default() });
+        cpu.load(&assemble("MovImm R3 3\nbody: Inc R0\nLoop body\nHLT\n")).unwrap();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+        let csv = instruction_stats_csv(cpu.instruction_counts.as_ref().unwrap(), cpu.instruction_cycles.as_ref().unwrap());
+        assert_eq!(csv, "mnemonic,count,cycles\nMovImm,1,1\nInc,3,3\nHLT,1,1\nLoop,3,3\n");
+    }
+
+    #[test]
+    fn memory_operands_cost_extra_cycles() {
+        let registers = run("Add R0 R1\nHLT\n");
+        let memory = run("Add M0 M1\nHLT\n");
+        assert_eq!(registers.cycles, 2);
+        assert_eq!(memory.cycles, 2 + 2 * MEMORY_OPERAND_CYCLES);
+        assert!(memory.cycles > registers.cycles);
+        assert_eq!(run("Mul R0 R1\nPush R0\nHLT\n").cycles, 3 + 3 + 1);
+    }
+
+    #[test]
+    fn stats_csv_reports_cycles_from_the_cost_table() {
+        let mut cpu = Cpu::with_options(&RunOptions { stats_csv: Some(String::new()), ..RunOptions::default() });
+        cpu.load(&assemble("Inc M0\nInc M0\nDiv R0 R1\nHLT\n")).unwrap();
+        let _ = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()); // Div stops on the zero divisor.
+        let csv = instruction_stats_csv(cpu.instruction_counts.as_ref().unwrap(), cpu.instruction_cycles.as_ref().unwrap());
+        assert_eq!(csv, "mnemonic,count,cycles\nInc,2,6\nDiv,1,6\n");
+    }
+
+    #[test]
+    fn ext_runs_the_registered_handler() {
+        let mut cpu = Cpu::new();
+        // Squares R<first byte>, ignoring the other two bytes.
+        cpu.set_extension_handler(|cpu, [register, _, _]| {
+            let value = cpu.register(register as usize);
+            cpu.set_register(register as usize, value.wrapping_mul(value));
+            Ok(())
+        });
+        cpu.load(&assemble("MovImm R0 7\nExt 0 0 0\nHLT\n")).unwrap();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+        assert_eq!(cpu.register(0), 49);
+    }
+
+    #[test]
+    fn ext_without_a_handler_is_a_runtime_error() {
+        let mut cpu = Cpu::new();
+        cpu.load(&assemble("Ext 1 2 3\nHLT\n")).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: No extension handler registered for Ext. PC: 0");
+    }
+
+    #[test]
+    fn ext_handler_errors_stop_the_program() {
+        let options = RunOptions { extension_handler: Some(|_, _| Err(String::from("not supported"))), ..RunOptions::default() };
+        let error = run_emulation(assemble("Ext 1 2 3\nHLT\n"), &options, &mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: Ext failed: not supported. PC: 0");
+    }
+
+    #[test]
+    fn folded_stacks_attribute_instructions_to_their_call_path() {
+        let source = "Call outer\nCall inner\nHLT\nouter: Inc R0\nCall inner\nRet\ninner: Inc R1\nInc R1\nRet\n";
+        let program = crate::asm::assemble_program(source, &OpcodeMap::default(), &MachineConfig::default(), 0, None).unwrap();
+        let mut cpu = Cpu::with_options(&RunOptions { flamegraph: Some(String::new()), ..RunOptions::default() });
+        cpu.load(&program.bytes).unwrap();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+        let folded = folded_stacks(cpu.call_stacks.as_ref().unwrap(), &program.labels);
+        assert_eq!(folded, "main 3\nmain;outer 3\nmain;outer;inner 3\nmain;inner 3\n");
+    }
+
+    #[test]
+    fn trace_range_limits_the_traced_instructions() {
+        let mut cpu = Cpu::with_options(&RunOptions { trace: true, trace_range: Some((4, 8)), ..RunOptions::default() });
+        cpu.load(&assemble("Inc R0\nInc R1\nInc R2\nInc R3\nHLT\n")).unwrap();
+        let mut diagnostics = Vec::new();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut diagnostics).unwrap();
+        assert_eq!(String::from_utf8(diagnostics).unwrap(), "[PC 4] Inc R1  -> R1 = 1\n[PC 8] Inc R2  -> R2 = 1\n");
+    }
+
+    #[test]
+    fn trace_shows_each_instruction_and_its_effect() {
+        let (_, trace) = run_with_diagnostics("MovImm R0 3\nCmpImm R0 5\nPush R0\nHLT\n", true);
+        assert_eq!(trace, "[PC 0] MovImm R0 3  -> R0 = 3\n[PC 4] CmpImm R0 5  -> ZF=0 CF=1 SF=1 OF=0\n[PC 8] Push R0  -> SP = 254\n[PC 12] HLT\n");
+    }
+
+    #[test]
+    fn trace_shows_effective_address_of_indirect_source() {
+        let (_, trace) = run_with_diagnostics("MovImm M37 5\nMovImm R1 37\nMov R0 [R1]\nHLT\n", true);
+        assert!(trace.contains("[PC 8] Mov R0 [R1]  ; [R1] = M37  -> R0 = 5\n"), "{}", trace);
+    }
+
+    #[test]
+    fn trace_shows_destination_cell_addressed_before_the_instruction() {
+        // Xchg changes R0 itself, so the cell must be the one R0 pointed at before.
+        let (_, trace) = run_with_diagnostics("MovImm R0 9\nXchg [R0] R0\nHLT\n", true);
+        assert!(trace.contains("[PC 4] Xchg [R0] R0  ; [R0] = M9  -> M9 = 9\n"), "{}", trace);
+    }
+
+    // Runs a program to completion without tracing and returns the CPU.
+    fn run(source: &str) -> Cpu {
+        run_with_diagnostics(source, false).0
+    }
+
+    #[test]
+    fn indirect_operands_are_checked_against_the_configured_memory_size() {
+        let options = RunOptions { machine: MachineConfig::new(128, 4).unwrap(), ..RunOptions::default() };
+        let mut cpu = Cpu::with_options(&options);
+        cpu.load(&lexer("MovImm R1 200\nMov R0 [R1]\nHLT\n", &OpcodeMap::default(), &options.machine, None).unwrap()).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: Invalid memory address 200 for Mov source operand. PC: 4");
+    }
+
+    #[test]
+    fn indirect_operands_address_ram_through_a_register() {
+        let cpu = run("MovImm R1 20\nMovImm [R1] 7\nMov R0 [R1]\nHLT\n");
+        assert_eq!(cpu.ram(20), 7);
+        assert_eq!(cpu.register(0), 7);
+    }
+
+    #[test]
+    fn loop_counts_down_r3() {
+        let cpu = run("MovImm R3 5\nbody: Inc R0\nLoop body\nHLT\n");
+        assert_eq!(cpu.register(0), 5);
+        assert_eq!(cpu.register(LOOP_COUNTER as usize), 0);
+        assert!(cpu.is_flag_set(FLAG_ZERO));
+    }
+
+    #[test]
+    fn loop_from_zero_runs_256_times() {
+        let cpu = run("body: Inc M0\nLoop body\nHLT\n");
+        assert_eq!(cpu.instructions_executed(), 513);
+        assert_eq!(cpu.ram(0), 0);
+    }
+
+    #[test]
+    fn loop_keeps_the_other_flags() {
+        let cpu = run("MovImm R0 255\nAddImm R0 1\nMovImm R3 1\nLoop 0\nHLT\n");
+        assert!(cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn abs_makes_values_non_negative() {
+        assert_eq!(run("MovImm R0 -5\nAbs R0\nHLT\n").register(0), 5);
+        let cpu = run("MovImm R0 5\nAbs R0\nHLT\n");
+        assert_eq!(cpu.register(0), 5);
+        assert!(!cpu.is_flag_set(FLAG_SIGN) && !cpu.is_flag_set(FLAG_OVERFLOW));
+    }
+
+    #[test]
+    fn abs_of_minus_128_overflows() {
+        let cpu = run("MovImm R0 -128\nAbs R0\nHLT\n");
+        assert_eq!(cpu.register(0), 0x80);
+        assert!(cpu.is_flag_set(FLAG_OVERFLOW));
+        assert!(cpu.is_flag_set(FLAG_SIGN));
+        assert!(!cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn neg_negates_and_sets_flags() {
+        let cpu = run("MovImm R0 5\nNeg R0\nHLT\n");
+        assert_eq!(cpu.register(0), 251);
+        assert!(cpu.is_flag_set(FLAG_SIGN) && cpu.is_flag_set(FLAG_CARRY) && !cpu.is_flag_set(FLAG_OVERFLOW));
+
+        let cpu = run("MovImm R0 1\nNeg R0\nHLT\n");
+        assert_eq!(cpu.register(0), 0xFF); // -1
+
+        let cpu = run("Neg R0\nHLT\n");
+        assert_eq!(cpu.register(0), 0);
+        assert!(cpu.is_flag_set(FLAG_ZERO) && !cpu.is_flag_set(FLAG_CARRY));
+
+        let cpu = run("MovImm R0 -128\nNeg R0\nHLT\n");
+        assert_eq!(cpu.register(0), 128);
+        assert!(cpu.is_flag_set(FLAG_OVERFLOW));
+    }
+
+    #[test]
+    fn cas_swaps_when_memory_holds_the_expected_value() {
+        let cpu = run("MovImm M7 3\nMovImm R0 3\nMovImm R1 9\nCas M7 R0 R1\nHLT\n");
+        assert_eq!(cpu.ram(7), 9);
+        assert!(cpu.is_flag_set(FLAG_ZERO));
+    }
+
+    #[test]
+    fn cas_leaves_memory_unchanged_when_it_holds_another_value() {
+        let cpu = run("MovImm M7 4\nMovImm R0 3\nMovImm R1 9\nCmp R0 R0\nCas M7 R0 R1\nHLT\n");
+        assert_eq!(cpu.ram(7), 4);
+        assert!(!cpu.is_flag_set(FLAG_ZERO)); // Cleared even though the Cmp before it set it.
+    }
+
+    #[test]
+    fn cas_address_in_the_mode_byte_is_bounds_checked() {
+        let mut cpu = Cpu::with_options(&RunOptions { machine: MachineConfig::new(128, 4).unwrap(), ..RunOptions::default() });
+        cpu.load(&[Instructions::Cas.opcode(), 200, 0, 1, Instructions::HLT.opcode(), 0, 0, 0]).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: Invalid memory address 200 for Cas address operand. PC: 0");
+    }
+
+    #[test]
+    fn imul_sets_signed_flags() {
+        let cpu = run("MovImm R0 -3\nMovImm R1 4\nIMul R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0) as i8, -12);
+        assert!(cpu.is_flag_set(FLAG_SIGN));
+        assert!(!cpu.is_flag_set(FLAG_OVERFLOW));
+        let cpu = run("MovImm R0 64\nMovImm R1 2\nIMul R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0) as i8, -128);
+        assert!(cpu.is_flag_set(FLAG_OVERFLOW));
+        assert!(!cpu.is_flag_set(FLAG_CARRY)); // Overflow is not copied into Carry.
+    }
+
+    #[test]
+    fn idiv_of_minus_128_by_minus_1_overflows() {
+        let cpu = run("MovImm R0 -128\nMovImm R1 -1\nIDiv R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0) as i8, -128);
+        assert!(cpu.is_flag_set(FLAG_OVERFLOW));
+        assert!(cpu.is_flag_set(FLAG_SIGN));
+        assert!(!cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn signed_operations_leave_carry_alone() {
+        // 255 + 1 sets Carry, which the signed division after it must not clear.
+        let cpu = run("MovImm R0 255\nAddImm R0 1\nMovImm R1 -6\nMovImm R2 2\nIDiv R1 R2\nHLT\n");
+        assert_eq!(cpu.register(1) as i8, -3);
+        assert!(cpu.is_flag_set(FLAG_CARRY));
+        assert!(!cpu.is_flag_set(FLAG_OVERFLOW));
+    }
+
+    #[test]
+    fn xchg_swaps_its_operands() {
+        let cpu = run("MovImm R0 1\nMovImm M4 2\nXchg R0 M4\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.ram(4)), (2, 1));
+    }
+
+    #[test]
+    fn xchg_with_itself_changes_nothing() {
+        let cpu = run("MovImm R0 9\nXchg R0 R0\nHLT\n");
+        assert_eq!(cpu.register(0), 9);
+    }
+
+    #[test]
+    fn xchg_swaps_two_memory_cells() {
+        let program = assemble("MovImm M0 1\nMovImm M1 2\nXchg M0 M1\nHLT\n");
+        assert_eq!(program[9], 0b11); // Both operands are memory.
+        let cpu = run("MovImm M0 1\nMovImm M1 2\nXchg M0 M1\nHLT\n");
+        assert_eq!((cpu.ram(0), cpu.ram(1)), (2, 1));
+    }
+
+    #[test]
+    fn xchg_of_a_memory_cell_with_itself_changes_nothing() {
+        let cpu = run("MovImm M5 7\nXchg M5 M5\nHLT\n");
+        assert_eq!(cpu.ram(5), 7);
+    }
+
+    #[test]
+    fn call_by_label_runs_the_subroutine_and_returns() {
+        let cpu = run("MovImm R0 21\nCall double\nHLT\ndouble: Add R0 R0\nRet\n");
+        assert_eq!(cpu.register(0), 42);
+        assert_eq!(cpu.pc(), 8); // Stopped at the HLT after the Call.
+    }
+
+    // Runs `CmpImm` of `a` with `b` and a `JmpGt` that sets R1 when taken, under the given borrow convention.
+    fn compare_and_jump_if_greater(a: u8, b: u8, inverted_borrow: bool) -> Cpu {
+        let mut cpu = Cpu::with_options(&RunOptions { inverted_borrow, ..RunOptions::default() });
+        let source = format!("MovImm R0 {}\nCmpImm R0 {}\nJmpGt greater\nHLT\ngreater: MovImm R1 1\nHLT\n", a, b);
+        cpu.load(&assemble(&source)).unwrap();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn comparison_sets_carry_on_borrow_by_default() {
+        let cpu = compare_and_jump_if_greater(3, 5, false);
+        assert!(cpu.is_flag_set(FLAG_CARRY)); // 3 - 5 borrows.
+        assert_eq!(cpu.register(1), 0);
+        let cpu = compare_and_jump_if_greater(5, 3, false);
+        assert!(!cpu.is_flag_set(FLAG_CARRY));
+        assert_eq!(cpu.register(1), 1);
+    }
+
+    #[test]
+    fn comparison_sets_carry_without_borrow_when_inverted() {
+        let cpu = compare_and_jump_if_greater(3, 5, true);
+        assert!(!cpu.is_flag_set(FLAG_CARRY));
+        assert_eq!(cpu.register(1), 0); // JmpGt follows the convention, so it still isn't taken.
+        let cpu = compare_and_jump_if_greater(5, 3, true);
+        assert!(cpu.is_flag_set(FLAG_CARRY));
+        assert_eq!(cpu.register(1), 1);
+    }
+
+    #[test]
+    fn rol16_rotates_the_pair_through_both_registers() {
+        let cpu = run("MovImm R0 0x80\nMovImm R1 0x01\nRol16 R0\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.register(1)), (0x00, 0x03));
+        assert!(cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn ror16_rotates_the_pair_through_both_registers() {
+        let cpu = run("MovImm R0 0x01\nMovImm R1 0x02\nRor16 R0\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.register(1)), (0x00, 0x81));
+        assert!(!cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn rol16_rejects_an_odd_register_pair() {
+        let mut cpu = Cpu::new();
+        cpu.load(&[Instructions::Rol16.opcode(), 0, 1, 0]).unwrap();
+        let error = cpu.step(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: Invalid register pair R1 for Rol16. PC: 0");
+    }
+
+    #[test]
+    fn misaligned_jump_target_is_rejected_at_runtime() {
+        let mut cpu = Cpu::new();
+        cpu.load(&[Instructions::JmpAddr.opcode(), 0, 3, 0, Instructions::HLT.opcode(), 0, 0, 0]).unwrap();
+        let error = cpu.step(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: Jump target 3 is not a multiple of the 4-byte instruction size. PC: 0");
+    }
+
+    #[test]
+    fn opcode_map_ignores_case_and_comments() {
+        let map = OpcodeMap::parse("mov 200 # moved\nMOVIMM 201 // also moved\n").unwrap();
+        assert_eq!(map.encode(Instructions::Mov), 200);
+        assert_eq!(map.encode(Instructions::MovImm), 201);
+    }
+
+    #[test]
+    fn von_neumann_program_can_modify_an_instruction_it_runs_later() {
+        // The first instruction turns the opcode of the `Inc` after it into `Dec`.
+        let source = format!("MovImm M4 {}\nInc R0\nHLT\n", Instructions::Dec.opcode());
+        let mut cpu = Cpu::with_options(&RunOptions { von_neumann: true, ..RunOptions::default() });
+        cpu.load(&assemble(&source)).unwrap();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+        assert_eq!(cpu.register(0), 255);
+
+        // With separate memories the same program writes RAM and leaves its code alone.
+        let cpu = run(&source);
+        assert_eq!((cpu.register(0), cpu.ram(4)), (1, Instructions::Dec.opcode()));
+    }
+
+    #[test]
+    fn swapped_opcodes_are_honored_by_assembly_and_execution() {
+        let (inc, dec) = (Instructions::Inc.opcode(), Instructions::Dec.opcode());
+        let map = OpcodeMap::parse(&format!("Inc {}\nDec {}\n", dec, inc)).unwrap();
+        let program = lexer("Inc R0\nHLT\n", &map, &MachineConfig::default(), None).unwrap();
+        assert_eq!(program[0], dec);
+
+        let mut cpu = Cpu::with_options(&RunOptions { opcode_map: map, ..RunOptions::default() });
+        cpu.load(&program).unwrap();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+        assert_eq!(cpu.register(0), 1); // Decoded as Inc with the same map.
+
+        let mut cpu = Cpu::new();
+        cpu.load(&program).unwrap();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+        assert_eq!(cpu.register(0), 255); // The default map reads the same byte as Dec.
+    }
+
+    #[test]
+    fn encoding_reference_describes_each_byte() {
+        let reference = encoding_reference();
+        let movimm = reference.split_once("\nMovImm <DEST> <VALUE>\n").unwrap().1;
+        let expected = format!("  byte 0 = opcode ({})\n", Instructions::MovImm.opcode());
+        assert!(movimm.starts_with(&expected), "{}", movimm);
+        assert_eq!(movimm.lines().nth(3), Some("  byte 3 = immediate value"));
+    }
+
+    // Runs a program to completion with the given options and returns the CPU.
+    fn run_with(options: &RunOptions, source: &str) -> Cpu {
+        let mut cpu = Cpu::with_options(options);
+        cpu.load(&assemble(source)).unwrap();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn execution_path_records_the_taken_branch() {
+        let options = RunOptions { record_path: true, ..RunOptions::default() };
+        let cpu = run_with(&options, "MovImm R0 1\nCmpImm R0 1\nJmpEq equal\nInc R1\nequal: HLT\n");
+        let path = [Instructions::MovImm, Instructions::CmpImm, Instructions::JmpEq, Instructions::HLT];
+        assert_eq!(cpu.execution_path.unwrap(), path);
+        let cpu = run_with(&options, "MovImm R0 2\nCmpImm R0 1\nJmpEq equal\nInc R1\nequal: HLT\n");
+        let path = [Instructions::MovImm, Instructions::CmpImm, Instructions::JmpEq, Instructions::Inc, Instructions::HLT];
+        assert_eq!(cpu.execution_path.unwrap(), path);
+    }
+
+    #[test]
+    fn hot_cfg_counts_loop_iterations_in_the_hot_block() {
+        let source = "MovImm R3 3\nbody: Inc R0\nLoop body\nHLT\n";
+        let cpu = run_with(&RunOptions { hot_cfg: true, ..RunOptions::default() }, source);
+        let graph = hot_cfg_graph(&assemble(source), 0, &OpcodeMap::default(), cpu.pc_counts.as_ref().unwrap());
+        assert!(graph.contains("    b4 [label=\"PC 4-11\\nInc\\nLoop\\nexecuted 3x\", style=filled, fillcolor=\"#ff9999\"];\n"), "{}", graph);
+        assert!(graph.contains("    b0 [label=\"PC 0-3\\nMovImm\\nexecuted 1x\"];\n"), "{}", graph);
+        assert!(graph.contains("    b4 -> b4;\n"), "{}", graph);
+    }
+
+    #[test]
+    fn overflow_report_lists_every_overflowing_addition() {
+        let program = assemble("MovImm R0 200\nAddImm R0 100\nMovImm R1 100\nAddImm R1 100\nHLT\n");
+        let options = RunOptions { report_overflows: true, ..RunOptions::default() };
+        let mut output = Vec::new();
+        run_emulation(program, &options, &mut std::io::empty(), &mut output, &mut std::io::sink()).unwrap();
+        let expected = "Overflow report: 2 event(s)\n  PC 4: AddImm 200 100 -> 44 (unsigned carry)\n  PC 12: AddImm 100 100 -> 200 (signed overflow)\n";
+        assert!(String::from_utf8(output).unwrap().ends_with(expected));
+    }
+
+    #[test]
+    fn clr_zeroes_its_operand_and_sets_zero() {
+        let cpu = run("MovImm R0 42\nClr R0\nHLT\n");
+        assert_eq!(cpu.register(0), 0);
+        assert!(cpu.is_flag_set(FLAG_ZERO));
+        assert_eq!(run("MovImm M3 7\nClr M3\nHLT\n").ram(3), 0);
+    }
+
+    #[test]
+    fn dbg_dumps_the_state_and_keeps_running() {
+        let (cpu, diagnostics) = run_with_diagnostics("Inc R0\nDbg\nInc R0\nHLT\n", false);
+        assert!(diagnostics.contains("CPU STATE AT PC 4"), "{}", diagnostics);
+        assert!(diagnostics.contains("reg1 = 1 (1)"), "{}", diagnostics);
+        assert_eq!(cpu.register(0), 2); // The instruction after Dbg still ran.
+    }
+
+    #[test]
+    fn ports_are_separate_from_ram() {
+        let program = assemble("InP R0 3\nOutP 4 R0\nOutP 4 M3\nHLT\n");
+        let options = RunOptions { port_inputs: vec![(3, vec![9])], ..RunOptions::default() };
+        let outcome = run_silently(&program, &options, 100).unwrap();
+        assert_eq!(outcome.error, None);
+        assert_eq!(outcome.registers[0], 9);
+        assert_eq!(outcome.port_outputs[4], [9, 0]);
+        assert_eq!(outcome.ram[3], 0); // Port 3 is not RAM cell 3.
+    }
+
+    #[test]
+    fn reading_an_empty_port_is_a_runtime_error() {
+        let outcome = run_silently(&assemble("InP R0 3\nHLT\n"), &RunOptions::default(), 100).unwrap();
+        assert_eq!(outcome.error.unwrap(), "Runtime error: No input available on port 3. PC: 0");
+    }
+
+    #[test]
+    fn trace_diff_mentions_only_what_changed() {
+        let mut cpu = Cpu::with_options(&RunOptions { trace_diff: true, ..RunOptions::default() });
+        cpu.load(&assemble("MovImm R0 1\nSub R0 R0\nHLT\n")).unwrap();
+        let mut diagnostics = Vec::new();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut diagnostics).unwrap();
+        assert_eq!(String::from_utf8(diagnostics).unwrap(), "[PC 0] MovImm R0 1  R0=1\n[PC 4] Sub R0 R0  R0=0 ZF=1\n");
+    }
+
+    #[test]
+    fn stop_at_output_pauses_right_after_the_first_out() {
+        let options = RunOptions { stop_at_output: true, ..RunOptions::default() };
+        let cpu = run_with(&options, "MovImm R0 5\nOut R0\nInc R0\nOut R0\nHLT\n");
+        assert_eq!(cpu.pc(), 8); // At the instruction after the Out.
+        assert_eq!(cpu.register(0), 5); // The Inc after it has not run.
+        assert_eq!(cpu.instructions_executed(), 2);
+    }
+
+    #[test]
+    fn set_eq_reflects_the_comparison() {
+        assert_eq!(run("MovImm R0 5\nCmpImm R0 5\nSetEq R1\nHLT\n").register(1), 1);
+        assert_eq!(run("MovImm R0 4\nCmpImm R0 5\nSetEq R1\nHLT\n").register(1), 0);
+        assert_eq!(run("MovImm R0 4\nCmpImm R0 5\nSetNe R1\nHLT\n").register(1), 1);
+        assert_eq!(run("MovImm R0 6\nCmpImm R0 5\nSetGt R1\nHLT\n").register(1), 1);
+    }
+
+    #[test]
+    fn jump_to_itself_is_detected_as_stuck() {
+        let mut cpu = Cpu::with_options(&RunOptions { detect_stuck: true, ..RunOptions::default() });
+        cpu.load(&assemble("Inc R0\nspin: JmpAddr spin\n")).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Infinite loop detected: PC 4 was reached again with identical state.");
+        assert_eq!(cpu.instructions_executed(), 3); // Caught within a few instructions, not at the cycle limit.
+    }
+
+    #[test]
+    fn loop_that_changes_state_is_not_stuck() {
+        let options = RunOptions { detect_stuck: true, ..RunOptions::default() };
+        assert_eq!(run_with(&options, "MovImm R3 5\nbody: Inc R0\nLoop body\nHLT\n").register(0), 5);
+    }
+
+    // Runs a program with the given `--budget` limits and returns its report.
+    fn budget_check(source: &str, code_budget: usize, data_budget: usize) -> String {
+        let options = RunOptions { code_budget: Some(code_budget), data_budget: Some(data_budget), ..RunOptions::default() };
+        let mut output = Vec::new();
+        run_emulation(assemble(source), &options, &mut std::io::empty(), &mut output, &mut std::io::sink()).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn program_within_both_budgets_passes() {
+        let report = budget_check("MovImm M3 1\nHLT\n", 8, 4);
+        assert!(report.ends_with("Code size: 8 bytes (budget 8) - ok\nData footprint: 4 bytes (budget 4) - ok\nBudget check passed.\n"), "{}", report);
+    }
+
+    #[test]
+    fn program_exceeding_the_data_budget_fails() {
+        let report = budget_check("MovImm M10 1\nHLT\n", 8, 4);
+        assert!(report.ends_with("Code size: 8 bytes (budget 8) - ok\nData footprint: 11 bytes (budget 4) - EXCEEDED\nBudget check failed.\n"), "{}", report);
+    }
+
+    #[test]
+    fn rust_const_lists_the_bytes_with_their_disassembly() {
+        let text = rust_const(&assemble("MovImm R0 5\nHLT\n"), "PROGRAM", &OpcodeMap::default());
+        let expected = format!(
+            "pub const PROGRAM: [u8; 8] = [\n    0x01, 0x00, 0x00, 0x05, // MovImm R0 5\n    0x{:02X}, 0x00, 0x00, 0x00, // HLT\n];\n",
+            Instructions::HLT.opcode()
+        );
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    fn mul_multiplies_and_sets_carry_when_the_product_overflows() {
+        let cpu = run("MovImm R0 3\nMovImm R1 4\nMul R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0), 12);
+        assert!(!cpu.is_flag_set(FLAG_CARRY));
+        let cpu = run("MovImm R0 255\nMovImm R1 2\nMul R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0), 254);
+        assert!(cpu.is_flag_set(FLAG_CARRY));
+        let cpu = run("MovImm R0 9\nMovImm M10 0\nMul R0 M10\nHLT\n");
+        assert_eq!(cpu.register(0), 0);
+        assert!(cpu.is_flag_set(FLAG_ZERO));
+    }
+
+    #[test]
+    fn first_touch_reports_the_first_write_of_each_location() {
+        let options = RunOptions { first_touch: true, ..RunOptions::default() };
+        let mut output = Vec::new();
+        let program = assemble("MovImm R0 1\nMovImm R1 2\nInc R0\nMovImm M3 4\nHLT\n");
+        run_emulation(program, &options, &mut std::io::empty(), &mut output, &mut std::io::sink()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with("First writes:\n  R0: PC 0\n  R1: PC 4\n  M3: PC 12\n"), "{}", output);
+    }
+
+    #[test]
+    fn div_stores_the_quotient_and_rejects_zero_divisors() {
+        assert_eq!(run("MovImm R0 10\nMovImm R1 3\nDiv R0 R1\nHLT\n").register(0), 3);
+        let cpu = run("MovImm R0 6\nMovImm M2 2\nDiv R0 M2\nHLT\n");
+        assert_eq!(cpu.register(0), 3);
+        assert!(!cpu.is_flag_set(FLAG_ZERO));
+        assert!(run("MovImm R0 2\nMovImm R1 3\nDiv R0 R1\nHLT\n").is_flag_set(FLAG_ZERO));
+
+        let mut cpu = Cpu::new();
+        cpu.load(&assemble("MovImm R0 5\nDiv R0 R1\nHLT\n")).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: division by zero. PC: 4");
+    }
+
+    #[test]
+    fn mod_stores_the_remainder_and_rejects_zero_divisors() {
+        let cpu = run("MovImm R0 7\nMovImm R1 3\nMod R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0), 1);
+        assert!(!cpu.is_flag_set(FLAG_ZERO));
+        let cpu = run("MovImm R0 8\nMovImm R1 4\nMod R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0), 0);
+        assert!(cpu.is_flag_set(FLAG_ZERO));
+
+        let mut cpu = Cpu::new();
+        cpu.load(&assemble("MovImm R0 5\nMod R0 R1\nHLT\n")).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: division by zero. PC: 4");
+    }
+
+    #[test]
+    fn code_dump_shows_the_assembled_bytes() {
+        let program = assemble("MovImm R0 1\nInc R0\nHLT\n");
+        let options = RunOptions { dump_code: Some((0, 19)), ..RunOptions::default() };
+        let mut output = Vec::new();
+        run_emulation(program.clone(), &options, &mut std::io::empty(), &mut output, &mut std::io::sink()).unwrap();
+        let hex: Vec<String> = program.iter().chain(&[0; 8]).map(|byte| format!("{:02X}", byte)).collect();
+        let expected = format!("Code memory 0-19:\n0000: {}\n0016: {}\n", hex[..16].join(" "), hex[16..20].join(" "));
+        assert!(String::from_utf8(output).unwrap().ends_with(&expected));
+    }
+
+    #[test]
+    fn bitwise_instructions_combine_bits_and_clear_carry() {
+        let cpu = run("MovImm R0 0b1100\nMovImm R1 0b1010\nAnd R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0), 0b1000);
+        assert_eq!(run("MovImm R0 0b1100\nMovImm M5 0b1010\nOr R0 M5\nHLT\n").register(0), 0b1110);
+        let cpu = run("MovImm R0 255\nAddImm R0 1\nMovImm R0 0b1010\nMovImm R1 0b1010\nXor R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0), 0);
+        assert!(cpu.is_flag_set(FLAG_ZERO));
+        assert!(!cpu.is_flag_set(FLAG_CARRY)); // Cleared although the AddImm before set it.
+    }
+
+    #[test]
+    fn not_inverts_every_bit() {
+        let cpu = run("Not R2\nHLT\n");
+        assert_eq!(cpu.register(2), 0xFF);
+        assert!(!cpu.is_flag_set(FLAG_ZERO));
+        let cpu = run("MovImm M0 0xFF\nNot M0\nHLT\n");
+        assert_eq!(cpu.ram(0), 0x00);
+        assert!(cpu.is_flag_set(FLAG_ZERO));
+    }
+
+    #[test]
+    fn shifts_move_the_last_bit_out_into_carry() {
+        let cpu = run("MovImm R0 0x80\nMovImm R1 1\nShl R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0), 0);
+        assert!(cpu.is_flag_set(FLAG_CARRY) && cpu.is_flag_set(FLAG_ZERO));
+        let cpu = run("MovImm R0 1\nMovImm R1 1\nShr R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0), 0);
+        assert!(cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn shift_counts_use_only_their_low_three_bits() {
+        let cpu = run("MovImm R0 255\nAddImm R0 1\nMovImm R0 0x41\nShl R0 R1\nHLT\n");
+        assert_eq!(cpu.register(0), 0x41); // A count of 0 leaves the value...
+        assert!(!cpu.is_flag_set(FLAG_CARRY)); // ...and clears Carry.
+        assert_eq!(run("MovImm R0 0x41\nMovImm R1 9\nShl R0 R1\nHLT\n").register(0), 0x82); // 9 acts as 1.
+    }
+
+    #[test]
+    fn add_imm_adds_a_constant_and_wraps_with_carry() {
+        let cpu = run("MovImm R0 250\nAddImm R0 5\nHLT\n");
+        assert_eq!(cpu.register(0), 255);
+        assert!(!cpu.is_flag_set(FLAG_CARRY));
+        let cpu = run("MovImm R0 250\nAddImm R0 5\nAddImm R0 1\nHLT\n");
+        assert_eq!(cpu.register(0), 0);
+        assert!(cpu.is_flag_set(FLAG_CARRY) && cpu.is_flag_set(FLAG_ZERO));
+    }
+
+    #[test]
+    fn sub_imm_subtracts_a_constant_and_borrows() {
+        let cpu = run("MovImm M4 9\nSubImm M4 3\nHLT\n");
+        assert_eq!(cpu.ram(4), 6);
+        assert!(!cpu.is_flag_set(FLAG_CARRY));
+        let cpu = run("MovImm R0 2\nSubImm R0 5\nHLT\n");
+        assert_eq!(cpu.register(0), 253);
+        assert!(cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn cmp_imm_sets_flags_without_storing() {
+        let cpu = run("MovImm R0 10\nCmpImm R0 10\nHLT\n");
+        assert_eq!(cpu.register(0), 10);
+        assert!(cpu.is_flag_set(FLAG_ZERO) && !cpu.is_flag_set(FLAG_CARRY));
+        let cpu = run("MovImm R0 3\nCmpImm R0 10\nHLT\n");
+        assert_eq!(cpu.register(0), 3);
+        assert!(!cpu.is_flag_set(FLAG_ZERO) && cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn pop_returns_pushed_values_in_reverse_order() {
+        let cpu = run("MovImm R0 1\nMovImm R1 2\nPush R0\nPush R1\nPop R2\nPop R3\nHLT\n");
+        assert_eq!((cpu.register(2), cpu.register(3)), (2, 1));
+        assert_eq!(cpu.snapshot().stack_pointer, 255); // Back at the stack base.
+    }
+
+    #[test]
+    fn pop_from_an_empty_stack_is_an_underflow() {
+        let mut cpu = Cpu::new();
+        cpu.load(&assemble("Pop R0\nHLT\n")).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: stack underflow. PC: 0");
+    }
+
+    #[test]
+    fn call_pushes_the_return_address_and_ret_pops_it() {
+        let cpu = run("Call increment\nCall increment\nHLT\nincrement: Inc R0\nRet\n");
+        assert_eq!(cpu.register(0), 2);
+        assert_eq!(cpu.pc(), 8);
+        assert_eq!(cpu.ram(255), 8); // The return address of the second Call.
+        assert_eq!(cpu.snapshot().stack_pointer, 255);
+    }
+
+    #[test]
+    fn signed_jumps_treat_0xff_as_minus_one() {
+        let source = |jump: &str| format!("MovImm R0 -1\nCmpImm R0 1\n{} taken\nHLT\ntaken: MovImm R1 1\nHLT\n", jump);
+        let cpu = run(&source("JmpLt"));
+        assert!(cpu.is_flag_set(FLAG_SIGN) && !cpu.is_flag_set(FLAG_OVERFLOW));
+        assert_eq!(cpu.register(1), 1); // -1 < 1
+        assert_eq!(run(&source("JmpLe")).register(1), 1);
+        assert_eq!(run(&source("JmpGe")).register(1), 0);
+        assert_eq!(run(&source("JmpGt")).register(1), 1); // Unsigned, 255 > 1.
+    }
+
+    #[test]
+    fn out_writes_to_the_given_writer() {
+        let program = assemble("MovImm R0 72\nOut R0\nOut R0 char\nMovImm M1 105\nOut M1 char\nHLT\n");
+        let mut output = Vec::new();
+        let mut diagnostics = Vec::new();
+        run_emulation(program, &RunOptions::default(), &mut std::io::empty(), &mut output, &mut diagnostics).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "72\nHiHalted.\n");
+        assert!(diagnostics.is_empty());
+    }
+
+    // Runs a program reading the given input and returns the CPU and the run's result.
+    fn run_with_input(source: &str, input: &str) -> (Cpu, Result<u64, String>) {
+        let mut cpu = Cpu::new();
+        cpu.load(&assemble(source)).unwrap();
+        let result = cpu.run(&mut input.as_bytes(), &mut std::io::sink(), &mut std::io::sink());
+        (cpu, result)
+    }
+
+    #[test]
+    fn in_reads_a_number_from_the_given_reader() {
+        let (cpu, result) = run_with_input("In R0\nIn M2\nHLT\n", "42\n 7 \n");
+        assert!(result.is_ok());
+        assert_eq!((cpu.register(0), cpu.ram(2)), (42, 7));
+    }
+
+    #[test]
+    fn in_fails_on_missing_or_invalid_input() {
+        let (_, result) = run_with_input("In R0\nIn R1\nHLT\n", "1\n");
+        assert_eq!(result.unwrap_err(), "Runtime error: No input left to read. PC: 4");
+        let (_, result) = run_with_input("In R0\nHLT\n", "256\n");
+        assert!(result.unwrap_err().starts_with("Runtime error: Invalid input '256' (expected a number from 0 to 255)"));
+    }
+
+    #[test]
+    fn run_emulation_returns_an_error_for_an_unknown_opcode() {
+        let mut program = assemble("HLT\n");
+        program[0] = 0xEE;
+        let result = run_emulation(program, &RunOptions::default(), &mut "".as_bytes(), &mut std::io::sink(), &mut std::io::sink());
+        assert_eq!(result, Err("Unknown instruction opcode: 238".to_string()));
+    }
+
+    #[test]
+    fn run_emulation_returns_ok_for_a_halting_program() {
+        let result = run_emulation(assemble("HLT\n"), &RunOptions::default(), &mut "".as_bytes(), &mut std::io::sink(), &mut std::io::sink());
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn step_executes_one_instruction_at_a_time() {
+        let mut cpu = Cpu::new();
+        cpu.load(&assemble("MovImm R0 5\nDec R0\nHLT\n")).unwrap();
+        let step = |cpu: &mut Cpu| cpu.step(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+        assert!(step(&mut cpu));
+        assert_eq!((cpu.register(0), cpu.pc()), (5, 4));
+        assert!(step(&mut cpu));
+        assert_eq!((cpu.register(0), cpu.pc(), cpu.flags() & FLAG_ZERO), (4, 8, 0));
+        assert!(!step(&mut cpu));
+        assert_eq!(cpu.instructions_executed(), 3);
+    }
+
+    #[test]
+    fn stepping_executes_one_instruction_per_enter_and_stops_on_q() {
+        let program = assemble("MovImm R0 7\nInc R0\nHLT\n");
+        let mut commands = "\nq\n".as_bytes();
+        let mut output = Vec::new();
+        run_stepping(program, &RunOptions::default(), &mut commands, &mut output, &mut std::io::sink()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("[PC 0] MovImm R0 7"), "{}", output);
+        let after = output.split("CPU STATE AFTER PC 0").nth(1).expect(&output);
+        assert!(after.contains("reg1 = 7 (7)"), "{}", output);
+        assert!(after.contains("[PC 4] Inc R0"), "{}", output);
+        assert!(!output.contains("CPU STATE AFTER PC 4"), "{}", output);
+    }
+
+    #[test]
+    fn disassembly_reassembles_to_the_same_bytes() {
+        let program = assemble("MovImm R1 42\nMov R0 M5\nAdd M3 R1\nloop: Dec R1\nJmpNe loop\nOut R0 char\nHLT\n");
+        let source = disassemble(&program, &OpcodeMap::default()).unwrap();
+        assert!(source.starts_with("MovImm R1 42;\nMov R0 M5;\nAdd M3 R1;\nDec R1;\nJmpNe 12;\n"), "{}", source);
+        assert_eq!(assemble(&source), program);
+    }
+
+    #[test]
+    fn disassembly_rejects_unknown_opcodes_and_truncated_instructions() {
+        let map = OpcodeMap::default();
+        assert_eq!(disassemble(&[0xEE, 0, 0, 0], &map).unwrap_err(), "Unknown instruction opcode: 238 at offset 0.");
+        let mut program = assemble("HLT\n");
+        program.truncate(2);
+        assert_eq!(disassemble(&program, &map).unwrap_err(), "Incomplete instruction at offset 0: only 2 of 4 bytes.");
+    }
+
+    #[test]
+    fn max_cycles_stops_an_infinite_loop_at_the_limit() {
+        let options = RunOptions { max_cycles: Some(50), ..RunOptions::default() };
+        let mut cpu = Cpu::with_options(&options);
+        cpu.load(&assemble("Inc R0\nJmpAddr 0\n")).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Execution limit exceeded: 50 instructions executed without halting. PC: 0");
+        assert_eq!((cpu.instructions_executed(), cpu.register(0)), (50, 25));
+    }
+
+    // Runs a program through `run_emulation` and returns everything it printed.
+    fn emulation_output(options: &RunOptions, source: &str) -> String {
+        let mut output = Vec::new();
+        run_emulation(assemble(source), options, &mut std::io::empty(), &mut output, &mut std::io::sink()).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn print_state_reports_the_instructions_executed() {
+        let options = RunOptions { print_state: true, ..RunOptions::default() };
+        let output = emulation_output(&options, "MovImm R0 3\nloop: Dec R0\nJmpNe loop\nHLT\n");
+        assert!(output.contains("Instructions executed: 8\n"), "{}", output);
+    }
+
+    #[test]
+    fn profile_counts_each_instruction() {
+        let options = RunOptions { profile: true, ..RunOptions::default() };
+        let output = emulation_output(&options, "MovImm R0 3\nloop: Dec R0\nJmpNe loop\nHLT\n");
+        let profile = output.split("Instruction profile:\n").nth(1).expect(&output);
+        assert_eq!(profile, "  Dec: 3 (3 cycles)\n  JmpNe: 3 (3 cycles)\n  MovImm: 1 (1 cycles)\n  HLT: 1 (1 cycles)\n");
+    }
+
+    #[test]
+    fn reserved_mode_bits_are_a_runtime_error() {
+        let run_bytes = |program: &[u8]| {
+            let mut cpu = Cpu::new();
+            cpu.load(program).unwrap();
+            cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err()
+        };
+        let inc = Instructions::Inc.opcode();
+        let hlt = Instructions::HLT.opcode();
+        assert_eq!(run_bytes(&[inc, 0x40, 0, 0, hlt, 0, 0, 0]), "Runtime error: Invalid mode byte 0x40 for Inc. PC: 0");
+        assert_eq!(run_bytes(&[inc, 0, 0, 0, hlt, 0x01, 0, 0]), "Runtime error: Invalid mode byte 0x01 for HLT. PC: 4");
+        let jump = Instructions::JmpAddr.opcode();
+        assert_eq!(run_bytes(&[jump, 0x02, 4, 0, hlt, 0, 0, 0]), "Runtime error: Invalid mode byte 0x02 for JmpAddr. PC: 0");
+    }
+
+    #[test]
+    fn running_off_the_end_without_hlt_is_an_error() {
+        let mut cpu = Cpu::new();
+        cpu.load(&assemble("MovImm R0 1\n")).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: Program counter ran past end of program without HLT. PC: 4");
+        assert_eq!(cpu.register(0), 1);
+    }
+
+    #[test]
+    fn a_program_ending_in_hlt_runs_cleanly() {
+        let mut cpu = Cpu::new();
+        cpu.load(&assemble("MovImm R0 1\nHLT\n")).unwrap();
+        assert!(cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).is_ok());
+    }
+
+    #[test]
+    fn pc_overflow_at_the_end_of_a_full_program_is_an_error() {
+        let mut cpu = Cpu::new();
+        cpu.load(&assemble(&"Inc R0\n".repeat(64))).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: Program counter overflow: no instruction can follow the end of the 256-byte address space. PC: 252");
+        assert_eq!(cpu.register(0), 64);
+    }
+
+    #[test]
+    fn jumps_outside_the_program_are_an_error() {
+        let mut cpu = Cpu::new();
+        cpu.load(&[Instructions::JmpAddr.opcode(), 0, 8, 0, Instructions::HLT.opcode(), 0, 0, 0]).unwrap();
+        let error = cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap_err();
+        assert_eq!(error, "Runtime error: Jump target 8 is outside the 8-byte program. PC: 0");
+    }
+
+    #[test]
+    fn state_shows_values_unsigned_and_signed() {
+        assert_eq!(format_unsigned_signed(255), "255 (-1)");
+        assert_eq!(format_unsigned_signed(128), "128 (-128)");
+        assert_eq!(format_unsigned_signed(127), "127 (127)");
+        assert_eq!(format_unsigned_signed(0), "0 (0)");
+        let cpu = run("MovImm R0 255\nMov M0 R0\nHLT\n");
+        let state = format_state(&cpu, "STATE");
+        assert!(state.contains("reg1 = 255 (-1), reg2 = 0 (0)"), "{}", state);
+        assert!(state.contains("RAM contents (first 10 bytes): [255 (-1), 0 (0)"), "{}", state);
+    }
+
+    #[test]
+    fn jmp_c_branches_when_an_add_overflows() {
+        let cpu = run("MovImm R0 200\nMovImm R1 100\nAdd R0 R1\nJmpC carried\nMovImm R2 1\nHLT\ncarried: MovImm R2 2\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.register(2)), (44, 2));
+        let cpu = run("MovImm R0 100\nMovImm R1 100\nAdd R0 R1\nJmpC carried\nMovImm R2 1\nHLT\ncarried: MovImm R2 2\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.register(2)), (200, 1));
+    }
+
+    #[test]
+    fn jmp_nc_branches_when_carry_is_clear() {
+        let cpu = run("MovImm R0 1\nAddImm R0 1\nJmpNc clear\nMovImm R2 1\nHLT\nclear: MovImm R2 2\nHLT\n");
+        assert_eq!(cpu.register(2), 2);
+        let cpu = run("MovImm R0 255\nAddImm R0 1\nJmpNc clear\nMovImm R2 1\nHLT\nclear: MovImm R2 2\nHLT\n");
+        assert_eq!(cpu.register(2), 1);
+    }
+
+    #[test]
+    fn jmp_rel_skips_forward() {
+        // The offset counts from the jump's own address, so 8 skips the instruction after it.
+        let cpu = run("JmpRel 8\nInc R0\nInc R1\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.register(1)), (0, 1));
+    }
+
+    #[test]
+    fn jmp_rel_loops_backward() {
+        let cpu = run("MovImm R0 3\nInc R1\nDec R0\nJmpEq 20\nJmpRel -12\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.register(1)), (0, 3));
+    }
+
+    #[test]
+    fn test_of_disjoint_bits_sets_zero_and_keeps_operands() {
+        let cpu = run("MovImm R0 0b1010\nMovImm R1 0b0101\nCmpImm R0 20\nTest R0 R1\nHLT\n");
+        assert!(cpu.is_flag_set(FLAG_ZERO));
+        assert!(!cpu.is_flag_set(FLAG_CARRY)); // The borrow from the CmpImm is cleared.
+        assert_eq!((cpu.register(0), cpu.register(1)), (0b1010, 0b0101));
+    }
+
+    #[test]
+    fn test_of_overlapping_bits_clears_zero() {
+        let cpu = run("MovImm R0 0b1010\nMovImm R1 0b0110\nTest R0 R1\nHLT\n");
+        assert!(!cpu.is_flag_set(FLAG_ZERO));
+        assert_eq!((cpu.register(0), cpu.register(1)), (0b1010, 0b0110));
+    }
+
+    #[test]
+    fn watch_reports_each_write_to_the_address_once() {
+        let mut cpu = Cpu::with_options(&RunOptions { watch: vec![5], ..RunOptions::default() });
+        set_operand_value(&mut cpu, OperandType::Memory, 5, 7, "test").unwrap();
+        set_operand_value(&mut cpu, OperandType::Memory, 6, 1, "test").unwrap();
+        set_operand_value(&mut cpu, OperandType::Memory, 5, 9, "test").unwrap();
+        assert_eq!(cpu.pending_diagnostics, "Watch: M5 written at PC 0: 0 -> 7\nWatch: M5 written at PC 0: 7 -> 9\n");
+    }
+
+    #[test]
+    fn watch_lines_go_to_the_diagnostics() {
+        let options = RunOptions { watch: vec![2], ..RunOptions::default() };
+        let mut cpu = Cpu::with_options(&options);
+        cpu.load(&assemble("MovImm R0 2\nMovImm R1 4\nMov M2 R1\nMov [R0] R0\nMov M3 R1\nHLT\n")).unwrap();
+        let mut diagnostics = Vec::new();
+        cpu.run(&mut std::io::empty(), &mut std::io::sink(), &mut diagnostics).unwrap();
+        let diagnostics = String::from_utf8(diagnostics).unwrap();
+        assert_eq!(diagnostics, "Watch: M2 written at PC 8: 0 -> 4\nWatch: M2 written at PC 12: 4 -> 2\n");
+    }
+
+    #[test]
+    fn restore_returns_to_the_snapshot_exactly() {
+        let mut cpu = Cpu::new();
+        cpu.load(&assemble("MovImm R0 5\nPush R0\nMov M3 R0\nDec R0\nCmpImm R0 9\nHLT\n")).unwrap();
+        let step = |cpu: &mut Cpu| cpu.step(&mut std::io::empty(), &mut std::io::sink(), &mut std::io::sink()).unwrap();
+        step(&mut cpu);
+        let checkpoint = cpu.snapshot();
+        for _ in 0..4 {
+            step(&mut cpu);
+        }
+        assert_ne!(cpu.snapshot(), checkpoint);
+        cpu.restore(&checkpoint);
+        assert_eq!(cpu.snapshot(), checkpoint);
+        assert_eq!((cpu.pc(), cpu.register(0), cpu.ram(3)), (4, 5, 0));
+    }
+
+    #[test]
+    fn json_state_lists_registers_flags_and_nonzero_ram() {
+        let options = RunOptions { json: true, print_state: true, ..RunOptions::default() };
+        let output = emulation_output(&options, "MovImm R0 3\nMovImm R1 200\nMov M4 R1\nCmp R0 R0\nHLT\n");
+        assert!(output.contains("  \"registers\": [3, 200, 0, 0],\n"), "{}", output);
+        assert!(output.contains("  \"flags\": {\"zero\": true, \"carry\": false, \"sign\": false, \"overflow\": false},\n"), "{}", output);
+        assert!(output.contains("  \"ram\": {\"4\": 200}\n"), "{}", output);
+        assert!(!output.contains("CPU STATE"), "{}", output);
+    }
+
+    #[test]
+    fn adc_chains_a_16_bit_addition() {
+        // 0x01FF + 0x0301 = 0x0500, low bytes in R0/R2 and high bytes in R1/R3.
+        let cpu = run("MovImm R0 0xFF\nMovImm R1 0x01\nMovImm R2 0x01\nMovImm R3 0x03\nAdd R0 R2\nAdc R1 R3\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.register(1)), (0x00, 0x05));
+        assert!(!cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn adc_carries_out_when_only_the_incoming_carry_overflows() {
+        // 0xFFFF + 0x0001: the high bytes 0xFF + 0x00 only overflow because of the carry from the low bytes.
+        let cpu = run("MovImm R0 0xFF\nMovImm R1 0xFF\nMovImm R2 0x01\nMovImm R3 0x00\nAdd R0 R2\nAdc R1 R3\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.register(1)), (0x00, 0x00));
+        assert!(cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn sbb_chains_a_16_bit_subtraction() {
+        // 0x0300 - 0x0101 = 0x01FF: the low bytes borrow, which the high bytes then subtract.
+        let cpu = run("MovImm R0 0x00\nMovImm R1 0x03\nMovImm R2 0x01\nMovImm R3 0x01\nSub R0 R2\nSbb R1 R3\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.register(1)), (0xFF, 0x01));
+        assert!(!cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn sbb_keeps_the_borrow_when_source_and_borrow_both_underflow() {
+        // 0x0000 - 0xFF01: the high bytes compute 0x00 - 0xFF - 1, which wraps to 0x00 and still borrows.
+        let cpu = run("MovImm R0 0x00\nMovImm R1 0x00\nMovImm R2 0x01\nMovImm R3 0xFF\nSub R0 R2\nSbb R1 R3\nHLT\n");
+        assert_eq!((cpu.register(0), cpu.register(1)), (0xFF, 0x00));
+        assert!(cpu.is_flag_set(FLAG_CARRY));
+    }
+
+    #[test]
+    fn clr_zeroes_the_destination_and_sets_zero() {
+        let cpu = run("MovImm R2 9\nMovImm R0 1\nCmpImm R0 5\nClr R2\nHLT\n");
+        assert_eq!(cpu.register(2), 0);
+        assert!(cpu.is_flag_set(FLAG_ZERO));
+        assert!(!cpu.is_flag_set(FLAG_CARRY)); // The borrow from the CmpImm is cleared.
+        let cpu = run("MovImm R0 7\nMov M1 R0\nClr M1\nHLT\n");
+        assert_eq!(cpu.ram(1), 0);
+        assert!(cpu.is_flag_set(FLAG_ZERO));
     }
 }